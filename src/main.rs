@@ -9,36 +9,94 @@ extern crate pretty_env_logger;
 extern crate log;
 
 use anyhow::Result;
-use chrono::{TimeZone, Utc};
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use clap::{ArgEnum, Parser, Subcommand};
+use mime_guess::mime::Mime;
+use rand::{distributions::Alphanumeric, Rng};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{fs::File, ops::Deref};
 use tabled::{Style, Table, Tabled};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
 use tokio::signal;
+use tokio::sync::{mpsc, oneshot, Semaphore};
 use url::Url;
 
 use matrix_sdk::{
+    attachment::{
+        AttachmentConfig, AttachmentInfo, BaseAudioInfo, BaseFileInfo, BaseImageInfo, BaseVideoInfo,
+    },
     config::{ClientConfig, SyncSettings},
-    room::Room,
+    encryption::verification::{SasVerification, Verification},
+    event_handler::RawEvent,
+    media::{MediaFormat, MediaRequest, MediaType},
+    room::{Common, Joined, Room},
     ruma::events::{
-        room::message::{
-            MessageType, RoomMessageEventContent, SyncRoomMessageEvent, TextMessageEventContent,
+        key::verification::{
+            cancel::ToDeviceKeyVerificationCancelEvent, done::ToDeviceKeyVerificationDoneEvent,
+            key::ToDeviceKeyVerificationKeyEvent, ready::ToDeviceKeyVerificationReadyEvent,
+            request::ToDeviceKeyVerificationRequestEvent, start::ToDeviceKeyVerificationStartEvent,
+        },
+        reaction::ReactionEventContent,
+        room::{
+            encrypted::RoomEncryptedEventContent,
+            member::RoomMemberEventContent,
+            message::{
+                AudioInfo, AudioMessageEventContent, EmoteMessageEventContent, FileInfo,
+                FileMessageEventContent, ImageMessageEventContent, MessageType,
+                NoticeMessageEventContent, Relation, RoomMessageEventContent, SyncRoomMessageEvent,
+                TextMessageEventContent, VideoInfo, VideoMessageEventContent,
+            },
+            redaction::SyncRoomRedactionEvent,
+            ImageInfo,
         },
-        AnyMessageEventContent,
+        AnyMessageEventContent, SyncMessageEvent, SyncStateEvent,
     },
     ruma::{
-        api::client::r0::{
-            alias::{
-                create_alias::Request as CreateRoomAliasRequest,
-                get_alias::Request as GetRoomAliasRequest,
+        api::client::{
+            error::ErrorKind,
+            r0::{
+                account::whoami::Request as WhoamiRequest,
+                alias::{
+                    create_alias::Request as CreateRoomAliasRequest,
+                    get_alias::Request as GetRoomAliasRequest,
+                },
+                backup::{
+                    add_backup_keys::Request as AddBackupKeysRequest,
+                    create_backup::Request as CreateBackupRequest,
+                    delete_backup::Request as DeleteBackupRequest,
+                    get_backup_info::Request as GetBackupInfoRequest,
+                    get_backup_keys::Request as GetBackupKeysRequest,
+                    get_latest_backup_info::Request as GetLatestBackupInfoRequest,
+                },
+                device::{update_device::Request as UpdateDeviceRequest, Device},
+                message::get_message_events::Request as GetMessageEventsRequest,
+                room::{
+                    create_room::{Request as CreateRoomRequest, RoomPreset},
+                    get_room_event::Request as GetRoomEventRequest,
+                },
+                session::{
+                    logout::Request as LogoutRequest, logout_all::Request as LogoutAllRequest,
+                },
+                uiaa::{AuthData, Password, UserIdentifier},
             },
-            room::create_room::{Request as CreateRoomRequest, RoomPreset},
         },
         identifiers::RoomName,
-        MxcUri, RoomAliasId, RoomId, RoomOrAliasId, RoomVersionId, ServerName, UserId,
+        serde::CanonicalJsonValue,
+        DeviceId, EventId, MxcUri, RoomAliasId, RoomId, RoomOrAliasId, RoomVersionId, ServerName,
+        UInt, UserId,
     },
-    Client,
+    Client, HttpError, LoopCtrl,
 };
+use matrix_sdk_crypto::{backups::RecoveryKey, olm::ExportedRoomKey, OlmMachine};
 
 /// matrix-cli
 ///
@@ -58,6 +116,20 @@ struct Cli {
     #[clap(short, long, env = "MATRIX_CLI_PASSWORD")]
     password: Option<String>,
 
+    /// Log in with single sign-on in the browser instead of a password, --password is then only
+    /// used by commands that ask for it again
+    #[clap(long)]
+    sso: bool,
+
+    /// Log in with an existing access token instead of a password, --password is then only used
+    /// by commands that ask for it again
+    #[clap(long, env = "MATRIX_CLI_ACCESS_TOKEN", conflicts_with = "sso")]
+    access_token: Option<String>,
+
+    /// Device id of the access token, when the homeserver does not tell us
+    #[clap(long, env = "MATRIX_CLI_DEVICE_ID", requires = "access-token")]
+    device_id: Option<String>,
+
     /// Use or store the session information here
     #[clap(short, long, env = "MATRIX_CLI_SESSION_FILE")]
     session_file: Option<PathBuf>,
@@ -66,6 +138,10 @@ struct Cli {
     #[clap(long, env = "MATRIX_CLI_STORE_PATH")]
     store_path: Option<PathBuf>,
 
+    /// Encrypt the state and encryption key stores with this passphrase
+    #[clap(long, env = "MATRIX_CLI_STORE_PASSPHRASE")]
+    store_passphrase: Option<String>,
+
     /// Print what will be done, without doing anything
     #[clap(long, env = "MATRIX_CLI_DRY_RUN")]
     dry_run: bool,
@@ -94,27 +170,361 @@ enum MatrixCli {
         #[clap(subcommand)]
         commands: Option<RoomCmd>,
     },
+    /// Run commands when messages match the triggers in a config file
+    #[clap(name = "bot")]
+    BotCmd {
+        /// Bot configuration file (TOML)
+        #[clap(name = "CONFIG")]
+        config: PathBuf,
+    },
+    /// Manage and verify devices
+    #[clap(name = "device")]
+    DeviceCmd {
+        #[clap(subcommand)]
+        commands: Option<DeviceCmd>,
+    },
+    /// Manage encryption keys
+    #[clap(name = "crypto")]
+    CryptoCmd {
+        #[clap(subcommand)]
+        commands: Option<CryptoCmd>,
+    },
+    /// Manage the login session
+    #[clap(name = "auth")]
+    AuthCmd {
+        #[clap(subcommand)]
+        commands: Option<AuthCmd>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AuthCmd {
+    /// Log out, invalidating the access token and deleting the session file
+    Logout {
+        /// Log out every device of this account
+        #[clap(long)]
+        all: bool,
+    },
+    /// Show the user id, device id and homeserver of this session
+    Whoami {},
+}
+
+#[derive(Subcommand, Debug)]
+enum CryptoCmd {
+    /// Create and upload cross signing keys, signing this device with them
+    BootstrapCrossSigning {},
+    /// Show the cross signing keys and the trust of our devices
+    Status {},
+    /// Export room keys to a passphrase protected file
+    ExportKeys {
+        /// File to write the keys to
+        #[clap(name = "FILE")]
+        file: PathBuf,
+        /// Passphrase to encrypt the file with
+        #[clap(long, env = "MATRIX_CLI_KEY_PASSPHRASE")]
+        passphrase: String,
+        /// Only export the keys for this room
+        #[clap(short, long)]
+        room: Option<String>,
+    },
+    /// Import room keys from a passphrase protected file
+    ImportKeys {
+        /// File to read the keys from
+        #[clap(name = "FILE")]
+        file: PathBuf,
+        /// Passphrase the file was encrypted with
+        #[clap(long, env = "MATRIX_CLI_KEY_PASSPHRASE")]
+        passphrase: String,
+    },
+    /// Manage the server-side backup of room keys
+    Backup {
+        #[clap(subcommand)]
+        commands: BackupCmd,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum BackupCmd {
+    /// Create a new key backup and upload the room keys we have to it
+    Create {
+        /// Protect the backup with a passphrase instead of printing a recovery key
+        #[clap(long, env = "MATRIX_CLI_BACKUP_PASSPHRASE")]
+        passphrase: Option<String>,
+    },
+    /// Download and import the room keys from a key backup
+    Restore {
+        /// Recovery key printed when the backup was created
+        #[clap(
+            long,
+            env = "MATRIX_CLI_RECOVERY_KEY",
+            required_unless_present = "passphrase"
+        )]
+        recovery_key: Option<String>,
+        /// Passphrase the backup was created with
+        #[clap(
+            long,
+            env = "MATRIX_CLI_BACKUP_PASSPHRASE",
+            conflicts_with = "recovery-key"
+        )]
+        passphrase: Option<String>,
+        /// Backup version, defaults to the latest backup
+        #[clap(long)]
+        version: Option<String>,
+    },
+    /// Show the latest key backup
+    Status {},
+    /// Delete a key backup
+    Delete {
+        /// Backup version, defaults to the latest backup
+        #[clap(long)]
+        version: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum DeviceCmd {
+    /// Verify a device with emoji (SAS), or wait for another device to ask for verification
+    Verify {
+        /// User id, only accept requests from this user when no device is given
+        #[clap(name = "USER")]
+        user: Option<String>,
+        /// Device id, send a verification request to this device
+        #[clap(name = "DEVICE", requires = "USER")]
+        device: Option<String>,
+    },
+    /// List the devices of this account
+    List {
+        /// Only list devices not seen for this long (e.g. 30d)
+        #[clap(long, parse(try_from_str = humantime::parse_duration))]
+        older_than: Option<Duration>,
+        /// Only list devices whose display name matches this regex
+        #[clap(long)]
+        name: Option<Regex>,
+    },
+    /// Set the display name of a device
+    Rename {
+        /// Device id
+        #[clap(name = "DEVICE")]
+        device: String,
+        /// New display name
+        #[clap(name = "NAME")]
+        name: String,
+    },
+    /// Delete (log out) devices, never the device in use
+    Delete {
+        /// Device ids to delete
+        #[clap(name = "DEVICE", required_unless_present_any = &["older-than", "name"])]
+        devices: Vec<String>,
+        /// Delete all devices not seen for this long (e.g. 30d)
+        #[clap(long, parse(try_from_str = humantime::parse_duration))]
+        older_than: Option<Duration>,
+        /// Delete all devices whose display name matches this regex
+        #[clap(long)]
+        name: Option<Regex>,
+    },
 }
 
 #[derive(Subcommand, Debug)]
 enum MessageCmd {
-    /// Listen for messages in a room
+    /// Listen for messages in one or more rooms
     Listen {
+        /// Room names or IDs
+        #[clap(name = "ROOM", required_unless_present = "all")]
+        rooms: Vec<String>,
+        /// Listen to every joined room
+        #[clap(long, conflicts_with = "ROOM")]
+        all: bool,
+        /// Output format
+        #[clap(short, long, arg_enum, default_value = "text")]
+        format: ListenFormat,
+        /// Kinds of events to show, separated by commas
+        #[clap(
+            long,
+            arg_enum,
+            use_delimiter = true,
+            require_delimiter = true,
+            default_value = "text"
+        )]
+        show: Vec<EventKind>,
+        /// Only show events from this sender (can be repeated)
+        #[clap(long = "sender", multiple_occurrences = true, number_of_values = 1)]
+        senders: Vec<String>,
+        /// Only show events whose body matches this regex
+        #[clap(long = "match")]
+        pattern: Option<Regex>,
+        /// Ignore events sent by this account
+        #[clap(long)]
+        ignore_own: bool,
+        /// Only show events sent after this date (RFC 3339 or YYYY-MM-DD) or duration ago (e.g. 10m)
+        #[clap(long, parse(try_from_str = parse_since))]
+        since: Option<DateTime<Utc>>,
+        /// Run this command for each event, with the event JSON on stdin
+        #[clap(long)]
+        exec: Option<String>,
+        /// Post what the --exec command prints back to the room as a reply, this also ignores
+        /// events sent by this account like --ignore-own
+        #[clap(long, requires = "exec")]
+        exec_reply: bool,
+        /// Kill the --exec command when it runs longer than this (e.g. 30s, 10m)
+        #[clap(long, default_value = "1m", parse(try_from_str = humantime::parse_duration))]
+        exec_timeout: Duration,
+        /// How many --exec commands may run at the same time, later events wait their turn
+        #[clap(long, default_value = "1")]
+        exec_max_concurrent: usize,
+    },
+    /// Wait for a matching message in a room, print it and exit
+    Wait {
         /// Room name or ID
         #[clap(name = "ROOM")]
         room: String,
+        /// Only match messages whose body matches this regex
+        #[clap(long = "match")]
+        pattern: Option<Regex>,
+        /// Only match messages from this sender (can be repeated)
+        #[clap(long = "sender", multiple_occurrences = true, number_of_values = 1)]
+        senders: Vec<String>,
+        /// Ignore messages sent by this account
+        #[clap(long)]
+        ignore_own: bool,
+        /// Give up and exit with an error after this long (e.g. 30s, 10m)
+        #[clap(long, parse(try_from_str = humantime::parse_duration))]
+        timeout: Option<Duration>,
+        /// Output format
+        #[clap(short, long, arg_enum, default_value = "text")]
+        format: ListenFormat,
     },
-    /// Send a plain text message to a room
+    /// Send a message to a room
     Send {
         /// Room name or ID
         #[clap(name = "ROOM")]
         room: String,
-        /// Message to send (plain text)
-        #[clap(name = "MSG")]
+        /// Message to send, use - to read it from stdin
+        #[clap(name = "MSG", required_unless_present = "file")]
+        msg: Option<String>,
+        /// Read the message from a file
+        #[clap(short, long, conflicts_with = "MSG")]
+        file: Option<PathBuf>,
+        /// Split long messages into several events
+        #[clap(long)]
+        split: bool,
+        /// Maximum size in bytes of each event when splitting
+        #[clap(long, default_value = "16384")]
+        max_size: usize,
+        /// Message type
+        #[clap(short = 't', long = "type", arg_enum, default_value = "text")]
+        msgtype: TextType,
+        /// Render the message as markdown
+        #[clap(long, conflicts_with = "html")]
+        markdown: bool,
+        /// Send the message as HTML, with a plain text fallback
+        #[clap(long)]
+        html: bool,
+        /// Send the message as a reply to this event
+        #[clap(long)]
+        reply_to: Option<String>,
+        /// Send the message in the thread started by this event
+        #[clap(long)]
+        thread: Option<String>,
+    },
+    /// Upload files and send them to a room
+    SendFile {
+        /// Room name or ID
+        #[clap(name = "ROOM")]
+        room: String,
+        /// Files to send
+        #[clap(name = "FILE", required = true)]
+        files: Vec<PathBuf>,
+        /// Caption, sent as a text message after the files
+        #[clap(short, long)]
+        caption: Option<String>,
+    },
+    /// Replace the content of a message
+    Edit {
+        /// Room name or ID
+        #[clap(name = "ROOM")]
+        room: String,
+        /// Event ID of the message to edit
+        #[clap(name = "EVENT_ID")]
+        event_id: String,
+        /// New message
+        #[clap(name = "NEW_BODY")]
         msg: String,
+        /// Message type
+        #[clap(short = 't', long = "type", arg_enum, default_value = "text")]
+        msgtype: TextType,
+        /// Render the message as markdown
+        #[clap(long, conflicts_with = "html")]
+        markdown: bool,
+        /// Send the message as HTML, with a plain text fallback
+        #[clap(long)]
+        html: bool,
+    },
+    /// Redact (remove) an event
+    Redact {
+        /// Reason
+        #[clap(short, long)]
+        reason: Option<String>,
+        /// Room name or ID
+        #[clap(name = "ROOM")]
+        room: String,
+        /// Event ID to redact
+        #[clap(name = "EVENT_ID")]
+        event_id: String,
+    },
+    /// React to a message
+    React {
+        /// Room name or ID
+        #[clap(name = "ROOM")]
+        room: String,
+        /// Event ID to react to
+        #[clap(name = "EVENT_ID")]
+        event_id: String,
+        /// Reaction key, usually an emoji
+        #[clap(name = "KEY")]
+        key: String,
+    },
+    /// Remove your reaction from a message
+    Unreact {
+        /// Room name or ID
+        #[clap(name = "ROOM")]
+        room: String,
+        /// Event ID the reaction was sent to
+        #[clap(name = "EVENT_ID")]
+        event_id: String,
+        /// Reaction key, usually an emoji
+        #[clap(name = "KEY")]
+        key: String,
+        /// How many events to search back through for the reaction
+        #[clap(short, long, default_value = "1000")]
+        limit: usize,
+    },
+    /// Show earlier messages in a room
+    History {
+        /// Room name or ID
+        #[clap(name = "ROOM")]
+        room: String,
+        /// Maximum number of messages to show
+        #[clap(short, long, default_value = "10")]
+        limit: usize,
+        /// Pagination token to start from (defaults to the latest message)
+        #[clap(long)]
+        from: Option<String>,
+        /// Only show messages sent before this date (RFC 3339 or YYYY-MM-DD)
+        #[clap(long, parse(try_from_str = parse_date))]
+        before: Option<DateTime<Utc>>,
+        /// Only show messages sent after this date (RFC 3339 or YYYY-MM-DD)
+        #[clap(long, parse(try_from_str = parse_date))]
+        after: Option<DateTime<Utc>>,
     },
 }
 
+#[derive(ArgEnum, Clone, Debug)]
+enum TextType {
+    Text,
+    Notice,
+    Emote,
+}
+
 #[derive(Subcommand, Debug)]
 enum UserCmd {
     /// Gets the users display name
@@ -182,6 +592,21 @@ enum RoomCmd {
         #[clap(short, long)]
         version: Option<String>,
     },
+    /// Export the full history of a room
+    Export {
+        /// Room name or ID
+        #[clap(name = "ROOM")]
+        room: String,
+        /// Archive format
+        #[clap(short, long, arg_enum, default_value = "json")]
+        format: ExportFormat,
+        /// Write the archive to this file
+        #[clap(short, long)]
+        output: PathBuf,
+        /// Download attachments into a directory next to the archive
+        #[clap(long)]
+        media: bool,
+    },
     /// Invite a user to a matrix room
     Invite {
         /// Room name or ID
@@ -217,6 +642,189 @@ enum RoomCmd {
     },
 }
 
+#[derive(ArgEnum, Clone, Debug)]
+enum ListenFormat {
+    Text,
+    Json,
+    Jsonl,
+    Raw,
+}
+
+#[derive(ArgEnum, Clone, Debug, PartialEq)]
+enum EventKind {
+    All,
+    Text,
+    Notice,
+    Emote,
+    Media,
+    Redaction,
+    Reaction,
+    Membership,
+    Encrypted,
+}
+
+/// Everything the listen event handlers need to decide what to print and how
+#[derive(Clone, Debug)]
+struct ListenOptions {
+    /// None listens to every joined room
+    room_ids: Option<Vec<Box<RoomId>>>,
+    format: ListenFormat,
+    show: Vec<EventKind>,
+    exec: Option<String>,
+    exec_reply: bool,
+    exec_timeout: Duration,
+    /// Limits how many --exec commands run at once
+    exec_running: Arc<Semaphore>,
+    /// Only these senders, everyone when empty
+    senders: Vec<String>,
+    pattern: Option<Regex>,
+    /// Our own user with --ignore-own or --exec-reply
+    ignored_user: Option<Box<UserId>>,
+    since: Option<DateTime<Utc>>,
+    /// Set by message wait, which only wants the first matching event
+    matched: Option<Arc<Mutex<Option<oneshot::Sender<()>>>>>,
+}
+
+impl ListenOptions {
+    fn includes_room(&self, room_id: &RoomId) -> bool {
+        match &self.room_ids {
+            None => true,
+            Some(room_ids) => room_ids.iter().any(|id| &**id == room_id),
+        }
+    }
+
+    fn includes_kind(&self, kind: &EventKind) -> bool {
+        self.show.contains(&EventKind::All) || self.show.contains(kind)
+    }
+
+    /// Apply the sender, --ignore-own, --since and --match filters
+    fn includes_event(&self, event: &Value, summary: &str) -> bool {
+        let sender = event["sender"].as_str().unwrap_or_default();
+        let ts = event["origin_server_ts"].as_i64().unwrap_or_default();
+        (self.senders.is_empty() || self.senders.iter().any(|s| s == sender))
+            && self
+                .ignored_user
+                .as_ref()
+                .map_or(true, |user_id| user_id.as_str() != sender)
+            && self
+                .since
+                .map_or(true, |since| ts >= since.timestamp_millis())
+            && self.pattern.as_ref().map_or(true, |pattern| {
+                pattern.is_match(&get_match_text(event, summary))
+            })
+    }
+}
+
+/// The text --match applies to. That is the message body without the quoted reply fallback,
+/// and the new body for edits, so quoting or editing a message does not match it again.
+/// Events without a body use their summary.
+fn get_match_text(event: &Value, summary: &str) -> String {
+    let content = &event["content"];
+    // a message that starts with a quote is not necessarily a reply
+    let is_reply = !content["m.relates_to"]["m.in_reply_to"].is_null();
+    let content = match content["m.relates_to"]["rel_type"].as_str() {
+        Some("m.replace") => &content["m.new_content"],
+        _ => content,
+    };
+    match content["body"].as_str() {
+        Some(body) if is_reply => strip_reply_fallback(body),
+        Some(body) => body.to_owned(),
+        None => summary.to_owned(),
+    }
+}
+
+#[derive(ArgEnum, Clone, Debug)]
+enum ExportFormat {
+    Json,
+    Jsonl,
+    Html,
+    Txt,
+}
+
+/// Bot configuration file, see the README for an example
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct BotConfig {
+    /// Rooms the bot answers in, every joined room when empty
+    #[serde(default)]
+    rooms: Vec<String>,
+    /// Users allowed to run any command, everyone when empty
+    #[serde(default)]
+    senders: Vec<String>,
+    /// Maximum number of commands running at the same time
+    #[serde(default = "default_bot_max_concurrent")]
+    max_concurrent: usize,
+    /// Default time a command may run for, e.g. "30s" or "5m"
+    #[serde(default = "default_bot_timeout")]
+    timeout: String,
+    #[serde(default, rename = "command")]
+    commands: Vec<BotCommandConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct BotCommandConfig {
+    /// Regex the message body has to match
+    trigger: String,
+    /// Shell command to run, the trigger captures are passed as MATRIX_MATCH_* variables
+    command: String,
+    /// Rooms this command may be run in, narrowing down the global list
+    #[serde(default)]
+    rooms: Vec<String>,
+    /// Users allowed to run this command, narrowing down the global list
+    #[serde(default)]
+    senders: Vec<String>,
+    /// Overrides the default timeout
+    timeout: Option<String>,
+    /// Post the command output back to the room as a reply
+    #[serde(default = "default_bot_reply")]
+    reply: bool,
+}
+
+fn default_bot_max_concurrent() -> usize {
+    1
+}
+
+fn default_bot_timeout() -> String {
+    "1m".to_owned()
+}
+
+fn default_bot_reply() -> bool {
+    true
+}
+
+/// A bot configuration with the room aliases resolved and the triggers compiled
+struct Bot {
+    user_id: Box<UserId>,
+    rooms: Vec<Box<RoomId>>,
+    senders: Vec<String>,
+    commands: Vec<BotCommand>,
+    running: Arc<Semaphore>,
+}
+
+struct BotCommand {
+    trigger: Regex,
+    command: String,
+    rooms: Vec<Box<RoomId>>,
+    senders: Vec<String>,
+    timeout: Duration,
+    reply: bool,
+}
+
+impl BotCommand {
+    /// An empty allow list allows everything
+    fn allows(&self, bot: &Bot, room_id: &RoomId, sender: &str) -> bool {
+        let room_allowed =
+            |rooms: &Vec<Box<RoomId>>| rooms.is_empty() || rooms.iter().any(|id| &**id == room_id);
+        let sender_allowed =
+            |senders: &Vec<String>| senders.is_empty() || senders.iter().any(|s| s == sender);
+        room_allowed(&bot.rooms)
+            && room_allowed(&self.rooms)
+            && sender_allowed(&bot.senders)
+            && sender_allowed(&self.senders)
+    }
+}
+
 #[derive(Tabled)]
 struct RoomRow {
     id: String,
@@ -224,6 +832,23 @@ struct RoomRow {
     description: String,
 }
 
+#[derive(Tabled)]
+struct DeviceRow {
+    id: String,
+    name: String,
+    last_seen_ip: String,
+    last_seen: String,
+    current: String,
+}
+
+#[derive(Tabled)]
+struct DeviceTrustRow {
+    id: String,
+    name: String,
+    trust: String,
+    current: String,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     pretty_env_logger::init();
@@ -232,51 +857,119 @@ async fn main() -> Result<(), anyhow::Error> {
     let homeserver_url = Url::parse(&homeserver_url_str).expect("Could not parse homeserver_url");
     let hostname = homeserver_url.host_str().unwrap();
 
+    let login_method = match (args.access_token, args.sso) {
+        (Some(access_token), _) => LoginMethod::AccessToken {
+            access_token,
+            device_id: args.device_id,
+        },
+        (None, true) => LoginMethod::Sso,
+        (None, false) => LoginMethod::Password {
+            username: args.username,
+            password: args.password.clone(),
+        },
+    };
     let client = login(
         args.homeserver_url,
-        args.username,
-        args.password,
-        args.session_file,
+        login_method,
+        args.session_file.clone(),
         args.store_path,
+        args.store_passphrase,
     )
     .await?;
 
     // sync will run forever, so wait for process_cmd to finish, then terminate
     tokio::select! {
         res = sync(&client) => res?,
-        res = process_cmd(args.dry_run, args.subcommands, &client, hostname) => res?,
+        res = process_cmd(
+            args.dry_run,
+            args.subcommands,
+            &client,
+            hostname,
+            args.password.as_deref(),
+            args.session_file.as_deref(),
+        ) => res?,
     }
     Ok(())
 }
 
+/// How login gets a new session when there is no saved one
+enum LoginMethod {
+    Password {
+        username: Option<String>,
+        password: Option<String>,
+    },
+    Sso,
+    AccessToken {
+        access_token: String,
+        device_id: Option<String>,
+    },
+}
+
 async fn login(
     homeserver_url_str: String,
-    username: Option<String>,
-    password: Option<String>,
+    login_method: LoginMethod,
     session_file: Option<PathBuf>,
     store_path: Option<PathBuf>,
-) -> Result<Client, matrix_sdk::Error> {
+    store_passphrase: Option<String>,
+) -> Result<Client> {
     let homeserver_url = Url::parse(&homeserver_url_str).expect("Could not parse homeserver_url");
+    // a given access token replaces the saved session
     let session_file_exists = match &session_file {
         None => false,
-        Some(sf) => sf.exists(),
+        Some(sf) => !matches!(login_method, LoginMethod::AccessToken { .. }) && sf.exists(),
     };
 
+    // the state and crypto stores both live in the store path, without one the encryption keys
+    // are lost when we exit and encrypted history can not be read on the next run
     let mut config = ClientConfig::new();
-    if let Some(store_path) = store_path {
-        config = config.store_path(store_path);
+    match store_path {
+        Some(store_path) => config = config.store_path(store_path),
+        None => warn!("No store path set, encryption keys will not be kept"),
+    };
+    if let Some(store_passphrase) = store_passphrase {
+        config = config.passphrase(store_passphrase);
     };
     let client = Client::new_with_config(homeserver_url.clone(), config)
         .await
         .expect("Could not connect to homeserver");
     match session_file_exists {
         false => {
-            let username = username.expect("Missing username");
-            let password = password.expect("Missing password");
-            info!("Logging in to {} as {:?}", homeserver_url, &username);
-            let _response = client
-                .login(&username, &password, None, Some("matrix-cli"))
-                .await?;
+            match login_method {
+                LoginMethod::AccessToken {
+                    access_token,
+                    device_id,
+                } => {
+                    info!("Logging in to {} with an access token", homeserver_url);
+                    let session =
+                        get_access_token_session(&homeserver_url, access_token, device_id).await?;
+                    client.restore_login(session).await?;
+                }
+                LoginMethod::Sso => {
+                    info!("Logging in to {} with single sign-on", homeserver_url);
+                    // the SDK listens on a loopback port for the loginToken redirect
+                    let _response = client
+                        .login_with_sso(
+                            |sso_url| async move {
+                                println!("Open this URL in your browser to log in: {}", sso_url);
+                                Ok(())
+                            },
+                            None,
+                            None,
+                            None,
+                            Some("matrix-cli"),
+                            None,
+                        )
+                        .await?;
+                }
+                LoginMethod::Password { username, password } => {
+                    let username = username.expect("Missing username");
+                    let password = password.expect("Missing password");
+                    info!("Logging in to {} as {:?}", homeserver_url, &username);
+                    let _response = client
+                        .login(&username, &password, None, Some("matrix-cli"))
+                        .await?;
+                }
+            };
 
             // Only write the session if the session_file is specified
             if session_file.is_some() {
@@ -308,13 +1001,67 @@ async fn login(
     client.sync_once(SyncSettings::default()).await.unwrap();
     info!("Initial sync complete.",);
 
+    if let Err(e) = resume_key_backup(&client).await {
+        warn!("Could not resume the key backup: {}", e);
+    }
+
     Ok(client)
 }
 
+/// Build a session for an access token, asking the homeserver who the token belongs to
+async fn get_access_token_session(
+    homeserver_url: &Url,
+    access_token: String,
+    device_id: Option<String>,
+) -> Result<matrix_sdk::Session> {
+    // join replaces the last path segment of the homeserver url unless it ends with a slash
+    let mut base = homeserver_url.clone();
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+    let url = base.join("_matrix/client/r0/account/whoami")?;
+    let response = reqwest::Client::new()
+        .get(url)
+        .bearer_auth(&access_token)
+        .send()
+        .await?;
+    let status = response.status();
+    let body: Value = serde_json::from_str(&response.text().await?).unwrap_or_default();
+    if !status.is_success() {
+        anyhow::bail!(
+            "Access token was rejected with {}: {}",
+            status,
+            body["error"].as_str().unwrap_or_default()
+        );
+    }
+
+    let user_id = body["user_id"].as_str().unwrap_or_default();
+    let user_id = <&UserId>::try_from(user_id)?.to_owned();
+    // older homeservers do not return the device id
+    let device_id = device_id.as_deref().or_else(|| body["device_id"].as_str());
+    let device_id: Box<DeviceId> = match device_id {
+        Some(device_id) => device_id.into(),
+        None => anyhow::bail!("The homeserver did not return a device id, use --device-id"),
+    };
+    Ok(matrix_sdk::Session {
+        access_token,
+        user_id,
+        device_id,
+    })
+}
+
 async fn sync(client: &Client) -> Result<(), matrix_sdk::Error> {
     info!("Starting forever sync",);
     let settings = SyncSettings::default().token(client.sync_token().await.unwrap());
-    client.sync(settings).await;
+    // room keys arrive with the sync, back them up when a key backup is enabled
+    client
+        .sync_with_callback(settings, |_| async move {
+            if let Err(e) = upload_room_key_backup(client).await {
+                warn!("Could not back up room keys: {}", e);
+            }
+            LoopCtrl::Continue
+        })
+        .await;
 
     Ok(())
 }
@@ -324,51 +1071,363 @@ async fn process_cmd(
     subcommands: Option<MatrixCli>,
     client: &Client,
     hostname: &str,
+    password: Option<&str>,
+    session_file: Option<&Path>,
 ) -> Result<(), anyhow::Error> {
     if let Some(scmd) = subcommands {
         match scmd {
             MatrixCli::MessageCmd { commands } => {
                 if let Some(cmd) = commands {
                     match cmd {
-                        MessageCmd::Send { room, msg } => {
+                        MessageCmd::Send {
+                            room,
+                            msg,
+                            file,
+                            split,
+                            max_size,
+                            msgtype,
+                            markdown,
+                            html,
+                            reply_to,
+                            thread,
+                        } => {
                             let room_id = get_room_id_from_alias_str(client, &room).await;
                             let mroom = client
                                 .get_joined_room(&room_id)
                                 .expect("User has not joined this room");
 
-                            let content = AnyMessageEventContent::RoomMessage(
-                                RoomMessageEventContent::text_plain(msg),
-                            );
+                            let body = read_message_body(msg, file)?;
+                            let chunks = match split {
+                                false => vec![body],
+                                true => split_message(&body, max_size),
+                            };
+                            let reply_to = reply_to.as_deref().map(get_event_id_from_str);
+                            let thread = thread.as_deref().map(get_event_id_from_str);
+                            let replied_event = match &reply_to {
+                                None => None,
+                                Some(event_id) => {
+                                    Some(get_event_json(client, &room_id, event_id).await?)
+                                }
+                            };
+                            for (i, msg) in chunks.into_iter().enumerate() {
+                                let mut content =
+                                    serde_json::to_value(RoomMessageEventContent::new(
+                                        build_text_message(msg, &msgtype, markdown, html),
+                                    ))?;
+                                // only the first chunk is the reply, the rest follow in order
+                                let reply_to = match i {
+                                    0 => reply_to.as_deref(),
+                                    _ => None,
+                                };
+                                // the fallback would quote ciphertext, so encrypted
+                                // events are replied to without one
+                                if let (Some(event_id), Some(replied_event)) =
+                                    (reply_to, &replied_event)
+                                {
+                                    if replied_event["type"] != "m.room.encrypted" {
+                                        add_reply_fallback(
+                                            &mut content,
+                                            &room_id,
+                                            event_id,
+                                            replied_event,
+                                        );
+                                    }
+                                }
+                                set_relation(&mut content, reply_to, thread.as_deref());
 
-                            mroom.send(content, None).await?;
+                                let response =
+                                    mroom.send_raw(content, "m.room.message", None).await?;
+                                println!("{}", response.event_id);
+                            }
                         }
-                        MessageCmd::Listen { room } => {
-                            client
-                                .register_event_handler(
-                                    |event: SyncRoomMessageEvent, room: Room| async move {
-                                        if let Room::Joined(_room) = room {
-                                            let sender = event.sender.clone();
-                                            let msg_body = match event.content.msgtype {
-                                                MessageType::Text(TextMessageEventContent {
-                                                    body,
-                                                    ..
-                                                }) => body,
-                                                _ => return,
-                                            };
-                                            let ts: i64 = event.origin_server_ts.get().into();
-                                            let date = Utc.timestamp_millis(ts);
-                                            println!(
-                                                "From: {}\nDate: {}\nMessage: {}\n",
-                                                sender, date, msg_body
-                                            );
-                                        }
-                                    },
-                                )
-                                .await;
+                        MessageCmd::SendFile {
+                            room,
+                            files,
+                            caption,
+                        } => {
+                            let room_id = get_room_id_from_alias_str(client, &room).await;
+                            let mroom = client
+                                .get_joined_room(&room_id)
+                                .expect("User has not joined this room");
+
+                            for file in files {
+                                let response = match mroom.is_encrypted() {
+                                    // the file itself has to be encrypted too, the SDK does that
+                                    true => {
+                                        let mime =
+                                            mime_guess::from_path(&file).first_or_octet_stream();
+                                        let body = get_file_name(&file);
+                                        let config = get_attachment_config(&file, &mime)?;
+                                        let mut reader = File::open(&file)?;
+                                        mroom
+                                            .send_attachment(&body, &mime, &mut reader, config)
+                                            .await?
+                                    }
+                                    false => {
+                                        let msgtype = upload_attachment(client, &file).await?;
+                                        let content = AnyMessageEventContent::RoomMessage(
+                                            RoomMessageEventContent::new(msgtype),
+                                        );
+                                        mroom.send(content, None).await?
+                                    }
+                                };
+                                println!("{}", response.event_id);
+                            }
+                            if let Some(caption) = caption {
+                                let content = AnyMessageEventContent::RoomMessage(
+                                    RoomMessageEventContent::text_plain(caption),
+                                );
+                                let response = mroom.send(content, None).await?;
+                                println!("{}", response.event_id);
+                            }
+                        }
+                        MessageCmd::Edit {
+                            room,
+                            event_id,
+                            msg,
+                            msgtype,
+                            markdown,
+                            html,
+                        } => {
+                            let room_id = get_room_id_from_alias_str(client, &room).await;
+                            let mroom = client
+                                .get_joined_room(&room_id)
+                                .expect("User has not joined this room");
+                            let event_id = get_event_id_from_str(&event_id);
+
+                            let new_content = serde_json::to_value(RoomMessageEventContent::new(
+                                build_text_message(msg, &msgtype, markdown, html),
+                            ))?;
+                            let content = build_replacement(new_content, &event_id);
+
+                            let response = mroom.send_raw(content, "m.room.message", None).await?;
+                            println!("{}", response.event_id);
+                        }
+                        MessageCmd::Redact {
+                            room,
+                            event_id,
+                            reason,
+                        } => {
+                            let room_id = get_room_id_from_alias_str(client, &room).await;
+                            let mroom = client
+                                .get_joined_room(&room_id)
+                                .expect("User has not joined this room");
+                            let event_id = get_event_id_from_str(&event_id);
+
+                            let response = mroom.redact(&event_id, reason.as_deref(), None).await?;
+                            println!("{}", response.event_id);
+                        }
+                        MessageCmd::React {
+                            room,
+                            event_id,
+                            key,
+                        } => {
+                            let room_id = get_room_id_from_alias_str(client, &room).await;
+                            let mroom = client
+                                .get_joined_room(&room_id)
+                                .expect("User has not joined this room");
+                            let event_id = get_event_id_from_str(&event_id);
+
+                            let content = json!({
+                                "m.relates_to": {
+                                    "rel_type": "m.annotation",
+                                    "event_id": event_id,
+                                    "key": key,
+                                }
+                            });
+                            let response = mroom.send_raw(content, "m.reaction", None).await?;
+                            println!("{}", response.event_id);
+                        }
+                        MessageCmd::Unreact {
+                            room,
+                            event_id,
+                            key,
+                            limit,
+                        } => {
+                            let room_id = get_room_id_from_alias_str(client, &room).await;
+                            let mroom = client
+                                .get_joined_room(&room_id)
+                                .expect("User has not joined this room");
+                            let event_id = get_event_id_from_str(&event_id);
+                            let user_id = client.user_id().await.expect("Not logged in");
+
+                            let mut from = client.sync_token().await.expect("Missing sync token");
+                            let mut seen = 0;
+                            let reaction = 'search: loop {
+                                let (events, end) = get_messages_page(&mroom, &from, 100).await?;
+                                seen += events.len();
+                                for event in events {
+                                    let relation = &event["content"]["m.relates_to"];
+                                    if event["type"] == "m.reaction"
+                                        && event["sender"] == user_id.as_str()
+                                        && relation["event_id"] == event_id.as_str()
+                                        && relation["key"] == key.as_str()
+                                        && event["unsigned"]["redacted_because"].is_null()
+                                    {
+                                        break 'search event["event_id"]
+                                            .as_str()
+                                            .map(str::to_owned);
+                                    }
+                                }
+                                match end {
+                                    Some(end) if seen < limit => from = end,
+                                    _ => break None,
+                                }
+                            };
+
+                            let reaction = reaction.ok_or_else(|| {
+                                anyhow::anyhow!("No {} reaction found on {}", key, event_id)
+                            })?;
+                            let reaction = get_event_id_from_str(&reaction);
+                            let response = mroom.redact(&reaction, None, None).await?;
+                            println!("{}", response.event_id);
+                        }
+                        MessageCmd::History {
+                            room,
+                            limit,
+                            from,
+                            before,
+                            after,
+                        } => {
+                            let room_id = get_room_id_from_alias_str(client, &room).await;
+                            let mroom = client.get_room(&room_id).expect("Unknown room");
+                            let mut from = match from {
+                                Some(from) => from,
+                                None => client.sync_token().await.expect("Missing sync token"),
+                            };
+                            let mut messages: Vec<Value> = Vec::new();
+                            let next = 'paging: loop {
+                                let page_size = (limit - messages.len()).clamp(1, 100) as u32;
+                                let (events, end) =
+                                    get_messages_page(&mroom, &from, page_size).await?;
+                                for event in events {
+                                    let ts = event["origin_server_ts"].as_i64().unwrap_or_default();
+                                    if after.map_or(false, |after| ts < after.timestamp_millis()) {
+                                        break 'paging None;
+                                    }
+                                    if event["type"] != "m.room.message"
+                                        || before
+                                            .map_or(false, |before| ts >= before.timestamp_millis())
+                                    {
+                                        continue;
+                                    }
+                                    messages.push(event);
+                                }
+                                match end {
+                                    Some(end) if messages.len() < limit => from = end,
+                                    end => break 'paging end,
+                                }
+                            };
+
+                            // pages come newest first, print them in the order they were sent
+                            for event in messages.iter().rev() {
+                                print_message(
+                                    None,
+                                    event["sender"].as_str().unwrap_or_default(),
+                                    event["origin_server_ts"].as_i64().unwrap_or_default(),
+                                    event["content"]["body"].as_str().unwrap_or_default(),
+                                );
+                            }
+                            if let Some(next) = next {
+                                eprintln!("Continue with --from {}", next);
+                            }
+                        }
+                        MessageCmd::Listen {
+                            rooms,
+                            all,
+                            format,
+                            show,
+                            exec,
+                            exec_reply,
+                            exec_timeout,
+                            exec_max_concurrent,
+                            senders,
+                            pattern,
+                            ignore_own,
+                            since,
+                        } => {
+                            let room_ids = match all {
+                                true => None,
+                                false => {
+                                    let mut room_ids: Vec<Box<RoomId>> = Vec::new();
+                                    for room in &rooms {
+                                        room_ids
+                                            .push(get_room_id_from_alias_str(client, room).await);
+                                    }
+                                    Some(room_ids)
+                                }
+                            };
+                            let opts = ListenOptions {
+                                room_ids,
+                                format,
+                                show,
+                                exec,
+                                exec_reply,
+                                exec_timeout,
+                                exec_running: Arc::new(Semaphore::new(exec_max_concurrent.max(1))),
+                                senders,
+                                pattern,
+                                // replies to our own replies would never end
+                                ignored_user: match ignore_own || exec_reply {
+                                    true => client.user_id().await,
+                                    false => None,
+                                },
+                                since,
+                                matched: None,
+                            };
+                            register_listen_handlers(client, opts).await;
 
-                            println!("Listening to room {}, Ctrl-C to stop", room);
+                            // keep stdout clean for the machine readable formats
+                            match all {
+                                true => eprintln!("Listening to all joined rooms, Ctrl-C to stop"),
+                                false => {
+                                    eprintln!("Listening to {}, Ctrl-C to stop", rooms.join(", "))
+                                }
+                            };
                             signal::ctrl_c().await.expect("Failed to listen for Ctrl-C");
-                            println!("Exiting.");
+                            eprintln!("Exiting.");
+                        }
+                        MessageCmd::Wait {
+                            room,
+                            pattern,
+                            senders,
+                            ignore_own,
+                            timeout,
+                            format,
+                        } => {
+                            let room_id = get_room_id_from_alias_str(client, &room).await;
+                            let (matched, wait) = oneshot::channel();
+                            let opts = ListenOptions {
+                                room_ids: Some(vec![room_id]),
+                                format,
+                                show: vec![EventKind::Text, EventKind::Notice, EventKind::Emote],
+                                exec: None,
+                                exec_reply: false,
+                                exec_timeout: Duration::default(),
+                                exec_running: Arc::new(Semaphore::new(0)),
+                                senders,
+                                pattern,
+                                ignored_user: match ignore_own {
+                                    true => client.user_id().await,
+                                    false => None,
+                                },
+                                since: None,
+                                matched: Some(Arc::new(Mutex::new(Some(matched)))),
+                            };
+                            register_listen_handlers(client, opts).await;
+
+                            eprintln!("Waiting for a message in {}", room);
+                            match timeout {
+                                None => wait.await?,
+                                Some(timeout) => {
+                                    tokio::time::timeout(timeout, wait).await.map_err(|_| {
+                                        anyhow::anyhow!(
+                                            "Timed out after {}",
+                                            humantime::format_duration(timeout)
+                                        )
+                                    })??
+                                }
+                            };
                         }
                     };
                 };
@@ -516,6 +1575,80 @@ async fn process_cmd(
                                 println!("{:?}", response);
                             }
                         }
+                        RoomCmd::Export {
+                            room,
+                            format,
+                            output,
+                            media,
+                        } => {
+                            let room_id = get_room_id_from_alias_str(client, &room).await;
+                            let mroom = client.get_room(&room_id).expect("Unknown room");
+                            let events = get_all_room_events(client, &mroom).await?;
+                            info!("Exporting {} events from {}", events.len(), room_id);
+
+                            let media_dir = match media {
+                                false => None,
+                                true => {
+                                    let mut dir = output.clone().into_os_string();
+                                    dir.push("_media");
+                                    let dir = PathBuf::from(dir);
+                                    std::fs::create_dir_all(&dir)?;
+                                    Some(dir)
+                                }
+                            };
+
+                            // display names change over time, so track them while replaying
+                            let mut names: HashMap<String, String> = HashMap::new();
+                            let mut records: Vec<Value> = Vec::new();
+                            for event in events {
+                                let sender =
+                                    event["sender"].as_str().unwrap_or_default().to_owned();
+                                if event["type"] == "m.room.member" {
+                                    let member = event["state_key"].as_str().unwrap_or_default();
+                                    match event["content"]["displayname"].as_str() {
+                                        Some(name) => {
+                                            names.insert(member.to_owned(), name.to_owned())
+                                        }
+                                        None => names.remove(member),
+                                    };
+                                }
+                                let sender_name = names.get(&sender).cloned().unwrap_or(sender);
+
+                                // encrypted attachments keep their url in file
+                                let media_url = event["content"]["url"]
+                                    .as_str()
+                                    .or_else(|| event["content"]["file"]["url"].as_str());
+                                let media_path = match (&media_dir, media_url) {
+                                    (Some(dir), Some(url)) => {
+                                        let file_name = sanitize_file_name(&format!(
+                                            "{}-{}",
+                                            event["event_id"].as_str().unwrap_or_default(),
+                                            event["content"]["body"].as_str().unwrap_or("media"),
+                                        ));
+                                        let path = dir.join(file_name);
+                                        match download_media(client, &event["content"], &path).await
+                                        {
+                                            Ok(()) => Some(path.display().to_string()),
+                                            Err(e) => {
+                                                warn!("Could not download {}: {}", url, e);
+                                                None
+                                            }
+                                        }
+                                    }
+                                    _ => None,
+                                };
+
+                                records.push(json!({
+                                    "sender_name": sender_name,
+                                    "media_path": media_path,
+                                    "event": event,
+                                }));
+                            }
+
+                            let mut file = File::create(&output)?;
+                            write_export(&mut file, &format, &room_id, &records)?;
+                            println!("Exported {} events to {}", records.len(), output.display());
+                        }
                         RoomCmd::Invite { room, user } => {
                             let room_id = get_room_id_from_alias_str(client, &room).await;
                             let room = client
@@ -554,12 +1687,789 @@ async fn process_cmd(
                     }
                 }
             }
-        }
-    };
+            MatrixCli::BotCmd { config } => {
+                let config: BotConfig = toml::from_str(&std::fs::read_to_string(&config)?)?;
+                let bot = load_bot(client, config).await?;
+                let command_count = bot.commands.len();
+                let bot = Arc::new(bot);
+                client
+                    .register_event_handler(move |event: SyncRoomMessageEvent, room: Room| {
+                        let bot = bot.clone();
+                        async move { handle_bot_message(bot, event, room) }
+                    })
+                    .await;
 
+                eprintln!(
+                    "Bot running with {} commands, Ctrl-C to stop",
+                    command_count
+                );
+                signal::ctrl_c().await.expect("Failed to listen for Ctrl-C");
+                eprintln!("Exiting.");
+            }
+            MatrixCli::DeviceCmd { commands } => {
+                if let Some(cmd) = commands {
+                    match cmd {
+                        DeviceCmd::Verify { user, device } => {
+                            let user_id = user.map(|user| {
+                                <&UserId>::try_from(user.deref())
+                                    .expect("Invalid user name")
+                                    .to_owned()
+                            });
+                            verify_device(client, user_id, device).await?;
+                        }
+                        DeviceCmd::List { older_than, name } => {
+                            let own_device_id = client.device_id().await;
+                            let mut data: Vec<DeviceRow> = Vec::new();
+                            for device in get_devices(client, older_than, name.as_ref()).await? {
+                                let current = own_device_id
+                                    .as_deref()
+                                    .map_or(false, |id| id == &*device.device_id);
+                                data.push(DeviceRow {
+                                    id: device.device_id.to_string(),
+                                    name: device.display_name.unwrap_or_default(),
+                                    last_seen_ip: device.last_seen_ip.unwrap_or_default(),
+                                    last_seen: device
+                                        .last_seen_ts
+                                        .map(|ts| {
+                                            Utc.timestamp_millis(u64::from(ts.get()) as i64)
+                                                .to_string()
+                                        })
+                                        .unwrap_or_default(),
+                                    current: match current {
+                                        true => "*".to_owned(),
+                                        false => "".to_owned(),
+                                    },
+                                });
+                            }
+                            let t = Table::new(&data).with(Style::PSQL);
+                            println!("{}", t);
+                        }
+                        DeviceCmd::Rename { device, name } => {
+                            let device_id: Box<DeviceId> = device.as_str().into();
+                            let mut request = UpdateDeviceRequest::new(&device_id);
+                            request.display_name = Some(name);
+                            client.send(request, None).await?;
+                            println!("Renamed device {}", device_id);
+                        }
+                        DeviceCmd::Delete {
+                            devices,
+                            older_than,
+                            name,
+                        } => {
+                            let own_device_id = client.device_id().await;
+                            let mut device_ids: Vec<Box<DeviceId>> =
+                                devices.iter().map(|id| id.as_str().into()).collect();
+                            if older_than.is_some() || name.is_some() {
+                                for device in get_devices(client, older_than, name.as_ref()).await?
+                                {
+                                    if !device_ids.contains(&device.device_id) {
+                                        device_ids.push(device.device_id);
+                                    }
+                                }
+                            }
+                            device_ids.retain(|id| Some(id) != own_device_id.as_ref());
+
+                            if device_ids.is_empty() {
+                                println!("No devices to delete");
+                            } else if dry_run {
+                                for device_id in &device_ids {
+                                    println!("Would delete device {}", device_id);
+                                }
+                            } else {
+                                if let Err(e) = client.delete_devices(&device_ids, None).await {
+                                    // deleting devices always needs the password again
+                                    let response = match e.uiaa_response() {
+                                        Some(response) => response,
+                                        None => return Err(e.into()),
+                                    };
+                                    let user_id = client.user_id().await.expect("Not logged in");
+                                    let password = match password {
+                                        Some(password) => password,
+                                        None => anyhow::bail!("Deleting devices needs --password"),
+                                    };
+                                    let auth_data = get_password_auth_data(
+                                        &user_id,
+                                        password,
+                                        response.session.as_deref(),
+                                    );
+                                    client.delete_devices(&device_ids, Some(auth_data)).await?;
+                                }
+                                for device_id in &device_ids {
+                                    println!("Deleted device {}", device_id);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            MatrixCli::CryptoCmd { commands } => {
+                if let Some(cmd) = commands {
+                    match cmd {
+                        CryptoCmd::BootstrapCrossSigning {} => {
+                            let user_id = client.user_id().await.expect("Not logged in");
+                            if let Err(e) = client.bootstrap_cross_signing(None).await {
+                                // the server wants us to authenticate again before uploading keys
+                                let response = match e.uiaa_response() {
+                                    Some(response) => response,
+                                    None => return Err(e.into()),
+                                };
+                                let password = match password {
+                                    Some(password) => password,
+                                    None => anyhow::bail!(
+                                        "Bootstrapping cross signing needs --password"
+                                    ),
+                                };
+                                let auth_data = get_password_auth_data(
+                                    &user_id,
+                                    password,
+                                    response.session.as_deref(),
+                                );
+                                client.bootstrap_cross_signing(Some(auth_data)).await?;
+                            }
+                            println!("Cross signing keys created for {}", user_id);
+                        }
+                        CryptoCmd::Status {} => {
+                            let user_id = client.user_id().await.expect("Not logged in");
+                            let own_device_id = client.device_id().await;
+                            let key_state = |present: bool| match present {
+                                true => "present",
+                                false => "missing",
+                            };
+                            match client.cross_signing_status().await {
+                                None => println!("Cross signing: not available"),
+                                Some(status) => {
+                                    println!("Master key: {}", key_state(status.has_master));
+                                    println!(
+                                        "Self-signing key: {}",
+                                        key_state(status.has_self_signing)
+                                    );
+                                    println!(
+                                        "User-signing key: {}",
+                                        key_state(status.has_user_signing)
+                                    );
+                                }
+                            };
+                            println!();
+
+                            let mut data: Vec<DeviceTrustRow> = Vec::new();
+                            for device in client.get_user_devices(&user_id).await?.devices() {
+                                let trust = if device.is_cross_signing_trusted() {
+                                    "cross-signed"
+                                } else if device.is_locally_trusted() {
+                                    "locally verified"
+                                } else {
+                                    "unverified"
+                                };
+                                let current = own_device_id
+                                    .as_deref()
+                                    .map_or(false, |id| id == device.device_id());
+                                data.push(DeviceTrustRow {
+                                    id: device.device_id().to_string(),
+                                    name: device.display_name().unwrap_or_default().to_owned(),
+                                    trust: trust.to_owned(),
+                                    current: match current {
+                                        true => "*".to_owned(),
+                                        false => "".to_owned(),
+                                    },
+                                });
+                            }
+                            let t = Table::new(&data).with(Style::PSQL);
+                            println!("{}", t);
+                        }
+                        CryptoCmd::ExportKeys {
+                            file,
+                            passphrase,
+                            room,
+                        } => {
+                            let room_id = match room {
+                                None => None,
+                                Some(room) => Some(get_room_id_from_alias_str(client, &room).await),
+                            };
+                            client
+                                .export_keys(file.clone(), &passphrase, |session| {
+                                    room_id
+                                        .as_deref()
+                                        .map_or(true, |room_id| session.room_id() == room_id)
+                                })
+                                .await?;
+                            println!("Exported room keys to {}", file.display());
+                        }
+                        CryptoCmd::ImportKeys { file, passphrase } => {
+                            let result = client.import_keys(file.clone(), &passphrase).await?;
+                            debug!("Key import result {:?}", result);
+                            println!("Imported room keys from {}", file.display());
+                        }
+                        CryptoCmd::Backup { commands } => match commands {
+                            BackupCmd::Create { passphrase } => {
+                                let (recovery_key, mut auth_data) = match &passphrase {
+                                    Some(passphrase) => {
+                                        let salt = random_string(32);
+                                        let key = RecoveryKey::from_passphrase(
+                                            passphrase,
+                                            &salt,
+                                            BACKUP_KEY_ITERATIONS,
+                                        );
+                                        let auth_data = json!({
+                                            "private_key_salt": salt,
+                                            "private_key_iterations": BACKUP_KEY_ITERATIONS,
+                                        });
+                                        (key, auth_data)
+                                    }
+                                    None => (RecoveryKey::new()?, json!({})),
+                                };
+                                let olm_machine = get_olm_machine(client).await?;
+                                let backup_key = recovery_key.megolm_v1_public_key();
+                                auth_data["public_key"] = backup_key.to_base64().into();
+                                // signed by this device and our master key, so other clients
+                                // can trust the backup
+                                let canonical = CanonicalJsonValue::try_from(auth_data.clone())?;
+                                let signatures = olm_machine.sign(&canonical.to_string()).await;
+                                auth_data["signatures"] = serde_json::to_value(&signatures)?;
+                                let algorithm = serde_json::from_value(json!({
+                                    "algorithm": BACKUP_ALGORITHM,
+                                    "auth_data": auth_data,
+                                }))?;
+                                let response = client
+                                    .send(CreateBackupRequest::new(algorithm), None)
+                                    .await?;
+                                let version = response.version;
+
+                                let printed_key = recovery_key.to_base58();
+                                enable_key_backup(&olm_machine, recovery_key, &version).await?;
+                                let count = upload_room_key_backup(client).await?;
+                                println!(
+                                    "Created key backup version {} with {} room keys",
+                                    version, count
+                                );
+                                if passphrase.is_none() {
+                                    println!("Recovery key: {}", printed_key);
+                                }
+                            }
+                            BackupCmd::Restore {
+                                recovery_key,
+                                passphrase,
+                                version,
+                            } => {
+                                let backup = get_backup_info(client, version.as_deref())
+                                    .await?
+                                    .ok_or_else(|| anyhow::anyhow!("No key backup found"))?;
+                                let version = backup["version"].as_str().unwrap_or_default();
+                                let auth_data = &backup["auth_data"];
+                                let recovery_key = match (recovery_key, passphrase) {
+                                    (Some(recovery_key), _) => {
+                                        RecoveryKey::from_base58(&recovery_key)?
+                                    }
+                                    (None, Some(passphrase)) => {
+                                        let salt = auth_data["private_key_salt"]
+                                            .as_str()
+                                            .ok_or_else(|| {
+                                                anyhow::anyhow!(
+                                                    "Key backup {} has no passphrase",
+                                                    version
+                                                )
+                                            })?;
+                                        let iterations = auth_data["private_key_iterations"]
+                                            .as_u64()
+                                            .unwrap_or(BACKUP_KEY_ITERATIONS as u64);
+                                        RecoveryKey::from_passphrase(
+                                            &passphrase,
+                                            salt,
+                                            iterations as u32,
+                                        )
+                                    }
+                                    (None, None) => unreachable!(),
+                                };
+                                let public_key = recovery_key.megolm_v1_public_key().to_base64();
+                                if auth_data["public_key"].as_str() != Some(public_key.as_str()) {
+                                    anyhow::bail!(
+                                        "Recovery key or passphrase does not match key backup {}",
+                                        version
+                                    );
+                                }
+
+                                let response = client
+                                    .send(GetBackupKeysRequest::new(version), None)
+                                    .await?;
+                                let mut keys = Vec::new();
+                                for (room_id, room) in response.rooms {
+                                    for (session_id, key_data) in room.sessions {
+                                        let key_data: Value =
+                                            serde_json::from_str(key_data.json().get())?;
+                                        let session_data = &key_data["session_data"];
+                                        let field = |name: &str| {
+                                            session_data[name]
+                                                .as_str()
+                                                .unwrap_or_default()
+                                                .to_owned()
+                                        };
+                                        // checks the mac before decrypting
+                                        let plaintext = match recovery_key.decrypt_v1(
+                                            field("ephemeral"),
+                                            field("mac"),
+                                            field("ciphertext"),
+                                        ) {
+                                            Ok(plaintext) => plaintext,
+                                            Err(e) => {
+                                                warn!("Skipping session {}: {}", session_id, e);
+                                                continue;
+                                            }
+                                        };
+                                        let mut key: Value = serde_json::from_str(&plaintext)?;
+                                        key["room_id"] = json!(room_id);
+                                        key["session_id"] = session_id.into();
+                                        keys.push(serde_json::from_value::<ExportedRoomKey>(key)?);
+                                    }
+                                }
+                                let count = keys.len();
+                                let olm_machine = get_olm_machine(client).await?;
+                                // from the backup, so they are not uploaded to it again
+                                let result = olm_machine.import_keys(keys, true, |_, _| {}).await?;
+                                debug!("Key import result {:?}", result);
+                                // new room keys of this store go to the same backup from now on
+                                enable_key_backup(&olm_machine, recovery_key, version).await?;
+                                println!(
+                                    "Restored {} room keys from key backup version {}",
+                                    count, version
+                                );
+                            }
+                            BackupCmd::Status {} => match get_backup_info(client, None).await? {
+                                None => println!("No key backup"),
+                                Some(backup) => {
+                                    let auth_data = &backup["auth_data"];
+                                    let protection = match auth_data.get("private_key_salt") {
+                                        Some(_) => "passphrase",
+                                        None => "recovery key",
+                                    };
+                                    println!(
+                                        "Version: {}",
+                                        backup["version"].as_str().unwrap_or_default()
+                                    );
+                                    println!(
+                                        "Algorithm: {}",
+                                        backup["algorithm"].as_str().unwrap_or_default()
+                                    );
+                                    println!("Room keys: {}", backup["count"]);
+                                    println!(
+                                        "Public key: {}",
+                                        auth_data["public_key"].as_str().unwrap_or_default()
+                                    );
+                                    println!("Protected by: {}", protection);
+                                    let olm_machine = get_olm_machine(client).await?;
+                                    let backup_keys =
+                                        olm_machine.backup_machine().get_backup_keys().await?;
+                                    let enabled = backup_keys.backup_version.as_deref()
+                                        == backup["version"].as_str();
+                                    println!(
+                                        "Used by this store: {}",
+                                        match enabled {
+                                            true => "yes",
+                                            false => "no",
+                                        }
+                                    );
+                                }
+                            },
+                            BackupCmd::Delete { version } => {
+                                let backup = get_backup_info(client, version.as_deref())
+                                    .await?
+                                    .ok_or_else(|| anyhow::anyhow!("No key backup found"))?;
+                                let version = backup["version"].as_str().unwrap_or_default();
+                                client.send(DeleteBackupRequest::new(version), None).await?;
+
+                                // stop uploading to a backup that is gone
+                                let olm_machine = get_olm_machine(client).await?;
+                                let backup_machine = olm_machine.backup_machine();
+                                let backup_keys = backup_machine.get_backup_keys().await?;
+                                if backup_keys.backup_version.as_deref() == Some(version) {
+                                    backup_machine.disable_backup().await?;
+                                    backup_machine.save_recovery_key(None, None).await?;
+                                }
+                                println!("Deleted key backup version {}", version);
+                            }
+                        },
+                    }
+                }
+            }
+            MatrixCli::AuthCmd { commands } => {
+                if let Some(cmd) = commands {
+                    match cmd {
+                        AuthCmd::Logout { all } => {
+                            match all {
+                                true => {
+                                    client.send(LogoutAllRequest::new(), None).await?;
+                                }
+                                false => {
+                                    client.send(LogoutRequest::new(), None).await?;
+                                }
+                            };
+                            // the access token in the session file is useless now
+                            if let Some(session_file) = session_file {
+                                if session_file.exists() {
+                                    std::fs::remove_file(session_file)?;
+                                }
+                            }
+                            match all {
+                                true => println!("Logged out all devices"),
+                                false => println!("Logged out"),
+                            };
+                        }
+                        AuthCmd::Whoami {} => {
+                            // ask the server, so invalid credentials fail here
+                            let response = client.send(WhoamiRequest::new(), None).await?;
+                            let device_id = client.device_id().await;
+                            println!("User id: {}", response.user_id);
+                            println!(
+                                "Device id: {}",
+                                device_id.as_deref().map_or("", |id| id.as_str())
+                            );
+                            println!("Homeserver: {}", client.homeserver().await);
+                        }
+                    }
+                }
+            }
+        }
+    };
+
+    Ok(())
+}
+
+/// The devices of this account, optionally only those not seen for older_than or whose display
+/// name matches name. Devices that were never seen count as old.
+async fn get_devices(
+    client: &Client,
+    older_than: Option<Duration>,
+    name: Option<&Regex>,
+) -> Result<Vec<Device>> {
+    let cutoff = match older_than {
+        Some(older_than) => Some(Utc::now() - chrono::Duration::from_std(older_than)?),
+        None => None,
+    };
+    let devices = client.devices().await?.devices;
+    Ok(devices
+        .into_iter()
+        .filter(|device| {
+            cutoff.map_or(true, |cutoff| {
+                device.last_seen_ts.map_or(true, |ts| {
+                    (u64::from(ts.get()) as i64) < cutoff.timestamp_millis()
+                })
+            })
+        })
+        .filter(|device| {
+            name.map_or(true, |name| {
+                name.is_match(device.display_name.as_deref().unwrap_or_default())
+            })
+        })
+        .collect())
+}
+
+/// Password authentication for endpoints behind user-interactive auth
+fn get_password_auth_data<'a>(
+    user_id: &'a UserId,
+    password: &'a str,
+    session: Option<&'a str>,
+) -> AuthData<'a> {
+    let mut password = Password::new(UserIdentifier::MatrixId(user_id.as_str()), password);
+    password.session = session;
+    AuthData::Password(password)
+}
+
+const BACKUP_ALGORITHM: &str = "m.megolm_backup.v1.curve25519-aes-sha2";
+const BACKUP_KEY_ITERATIONS: u32 = 500_000;
+
+async fn get_olm_machine(client: &Client) -> Result<OlmMachine> {
+    client
+        .olm_machine()
+        .await
+        .ok_or_else(|| anyhow::anyhow!("Encryption is not set up, log in first"))
+}
+
+/// The key backup with this version, or the latest one, shaped like the JSON the server sends:
+/// version, count, etag, algorithm and auth_data. None when there is no such backup.
+async fn get_backup_info(client: &Client, version: Option<&str>) -> Result<Option<Value>> {
+    let not_found = |e: &HttpError| e.client_api_error_kind() == Some(&ErrorKind::NotFound);
+    let (version, count, etag, algorithm) = match version {
+        None => match client.send(GetLatestBackupInfoRequest::new(), None).await {
+            Ok(response) => (
+                response.version,
+                response.count,
+                response.etag,
+                response.algorithm,
+            ),
+            Err(e) if not_found(&e) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        },
+        Some(version) => match client.send(GetBackupInfoRequest::new(version), None).await {
+            Ok(response) => (
+                response.version,
+                response.count,
+                response.etag,
+                response.algorithm,
+            ),
+            Err(e) if not_found(&e) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        },
+    };
+    let mut backup: Value = serde_json::from_str(algorithm.json().get())?;
+    backup["version"] = version.into();
+    backup["count"] = json!(count);
+    backup["etag"] = etag.into();
+    Ok(Some(backup))
+}
+
+/// Upload the room keys of our store to this backup, now and on later runs
+async fn enable_key_backup(
+    olm_machine: &OlmMachine,
+    recovery_key: RecoveryKey,
+    version: &str,
+) -> Result<()> {
+    let backup_key = recovery_key.megolm_v1_public_key();
+    backup_key.set_version(version.to_owned());
+    let backup_machine = olm_machine.backup_machine();
+    backup_machine.enable_backup_v1(backup_key).await?;
+    backup_machine
+        .save_recovery_key(Some(recovery_key), Some(version.to_owned()))
+        .await?;
+    Ok(())
+}
+
+/// Turn the key backup saved in the store back on, so room keys received in this run are
+/// backed up too
+async fn resume_key_backup(client: &Client) -> Result<()> {
+    let olm_machine = get_olm_machine(client).await?;
+    let backup_keys = olm_machine.backup_machine().get_backup_keys().await?;
+    if let (Some(recovery_key), Some(version)) =
+        (backup_keys.recovery_key, backup_keys.backup_version)
+    {
+        enable_key_backup(&olm_machine, recovery_key, &version).await?;
+        upload_room_key_backup(client).await?;
+    }
     Ok(())
 }
 
+/// Upload the room keys that are not in the enabled key backup yet. Returns how many were
+/// uploaded, nothing happens without an enabled backup.
+async fn upload_room_key_backup(client: &Client) -> Result<usize> {
+    let olm_machine = get_olm_machine(client).await?;
+    let mut count = 0;
+    while let Some((request_id, request)) = olm_machine.backup().await? {
+        count += request
+            .rooms
+            .values()
+            .map(|room| room.sessions.len())
+            .sum::<usize>();
+        let response = client
+            .send(
+                AddBackupKeysRequest::new(&request.version, request.rooms),
+                None,
+            )
+            .await?;
+        olm_machine
+            .mark_request_as_sent(&request_id, &response)
+            .await?;
+    }
+    Ok(count)
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// Run an interactive SAS verification. With a device we send the request, otherwise we wait
+/// for one (from user_id, if given) and accept it. Returns once the verification is done.
+async fn verify_device(
+    client: &Client,
+    user_id: Option<Box<UserId>>,
+    device: Option<String>,
+) -> Result<()> {
+    let (done, mut finished) = mpsc::unbounded_channel::<Result<(), String>>();
+
+    let filter = user_id.clone();
+    client
+        .register_event_handler(
+            move |ev: ToDeviceKeyVerificationRequestEvent, client: Client| {
+                let filter = filter.clone();
+                async move {
+                    if filter.map_or(false, |user_id| user_id != ev.sender) {
+                        return;
+                    }
+                    let flow_id = ev.content.transaction_id.as_str();
+                    if let Some(request) =
+                        client.get_verification_request(&ev.sender, flow_id).await
+                    {
+                        println!(
+                            "Accepting verification request from {} {}",
+                            ev.sender, ev.content.from_device
+                        );
+                        if let Err(e) = request.accept().await {
+                            warn!("Could not accept verification request: {}", e);
+                        }
+                    }
+                }
+            },
+        )
+        .await;
+    client
+        .register_event_handler(
+            |ev: ToDeviceKeyVerificationReadyEvent, client: Client| async move {
+                let flow_id = ev.content.transaction_id.as_str();
+                if let Some(request) = client.get_verification_request(&ev.sender, flow_id).await {
+                    // the side that sent the request starts the emoji verification
+                    if request.we_started() {
+                        if let Err(e) = request.start_sas().await {
+                            warn!("Could not start verification: {}", e);
+                        }
+                    }
+                }
+            },
+        )
+        .await;
+    client
+        .register_event_handler(
+            |ev: ToDeviceKeyVerificationStartEvent, client: Client| async move {
+                let flow_id = ev.content.transaction_id.as_str();
+                if let Some(Verification::SasV1(sas)) =
+                    client.get_verification(&ev.sender, flow_id).await
+                {
+                    if !sas.we_started() {
+                        if let Err(e) = sas.accept().await {
+                            warn!("Could not accept verification: {}", e);
+                        }
+                    }
+                }
+            },
+        )
+        .await;
+    let key_done = done.clone();
+    client
+        .register_event_handler(move |ev: ToDeviceKeyVerificationKeyEvent, client: Client| {
+            let done = key_done.clone();
+            async move {
+                let flow_id = ev.content.transaction_id.as_str();
+                if let Some(Verification::SasV1(sas)) =
+                    client.get_verification(&ev.sender, flow_id).await
+                {
+                    tokio::spawn(confirm_sas(sas, done));
+                }
+            }
+        })
+        .await;
+    let done_done = done.clone();
+    client
+        .register_event_handler(
+            move |ev: ToDeviceKeyVerificationDoneEvent, client: Client| {
+                let done = done_done.clone();
+                async move {
+                    let flow_id = ev.content.transaction_id.as_str();
+                    if let Some(Verification::SasV1(sas)) =
+                        client.get_verification(&ev.sender, flow_id).await
+                    {
+                        if sas.is_done() {
+                            let _ = done.send(Ok(()));
+                        }
+                    }
+                }
+            },
+        )
+        .await;
+    client
+        .register_event_handler(
+            move |ev: ToDeviceKeyVerificationCancelEvent, client: Client| {
+                let done = done.clone();
+                async move {
+                    // only the flows we know about, not some other verification of the sender
+                    let flow_id = ev.content.transaction_id.as_str();
+                    let known = client.get_verification(&ev.sender, flow_id).await.is_some()
+                        || client
+                            .get_verification_request(&ev.sender, flow_id)
+                            .await
+                            .is_some();
+                    if known {
+                        let _ = done.send(Err(format!(
+                            "Verification cancelled: {}",
+                            ev.content.reason
+                        )));
+                    }
+                }
+            },
+        )
+        .await;
+
+    match (&user_id, device) {
+        (Some(user_id), Some(device)) => {
+            let device = client
+                .get_device(user_id, <&DeviceId>::from(device.as_str()))
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Unknown device {} of {}", device, user_id))?;
+            device.request_verification().await?;
+            eprintln!("Verification request sent, accept it on the other device");
+        }
+        _ => eprintln!("Waiting for a verification request, Ctrl-C to stop"),
+    };
+
+    match finished.recv().await {
+        Some(Ok(())) => {
+            println!("Device verified");
+            Ok(())
+        }
+        Some(Err(e)) => anyhow::bail!(e),
+        None => anyhow::bail!("Verification stopped"),
+    }
+}
+
+/// Show the emoji (or numbers) of a SAS verification and ask whether they match
+async fn confirm_sas(sas: SasVerification, done: mpsc::UnboundedSender<Result<(), String>>) {
+    let device = sas.other_device();
+    println!("Verifying {} {}", device.user_id(), device.device_id());
+    match (sas.emoji(), sas.decimals()) {
+        (Some(emoji), _) => {
+            let symbols: Vec<String> = emoji.iter().map(|e| format!("{:^12}", e.symbol)).collect();
+            let names: Vec<String> = emoji
+                .iter()
+                .map(|e| format!("{:^12}", e.description))
+                .collect();
+            println!("{}\n{}", symbols.join(""), names.join(""));
+        }
+        (None, Some((first, second, third))) => println!("{} {} {}", first, second, third),
+        (None, None) => {
+            let _ = done.send(Err("No short authentication string available".to_owned()));
+            return;
+        }
+    };
+    print!("Do they match what the other device shows? [y/N] ");
+    let _ = std::io::stdout().flush();
+
+    let answer = tokio::task::spawn_blocking(|| {
+        let mut input = String::new();
+        std::io::stdin().read_line(&mut input).map(|_| input)
+    })
+    .await;
+    let confirmed = matches!(
+        answer,
+        Ok(Ok(input)) if matches!(input.trim().to_lowercase().as_str(), "y" | "yes")
+    );
+
+    let result = match confirmed {
+        true => sas.confirm().await,
+        false => sas.cancel().await,
+    };
+    match (result, confirmed) {
+        (Err(e), _) => {
+            let _ = done.send(Err(e.to_string()));
+        }
+        (Ok(()), false) => {
+            let _ = done.send(Err("The codes did not match".to_owned()));
+        }
+        // the other side may have confirmed first, otherwise we wait for their done event
+        (Ok(()), true) if sas.is_done() => {
+            let _ = done.send(Ok(()));
+        }
+        (Ok(()), true) => println!("Waiting for the other device to confirm"),
+    };
+}
+
 async fn get_room_id_from_alias_str(client: &Client, room_or_alias: &str) -> Box<RoomId> {
     let alias = get_room_id_or_alias_from_str(room_or_alias);
     get_room_id_from_alias(client, &alias).await
@@ -575,6 +2485,83 @@ fn get_room_alias_id_from_str(alias: &str) -> Box<RoomAliasId> {
     <&RoomAliasId>::try_from(alias).unwrap().to_owned()
 }
 
+/// The canonical alias of a room if it has one, otherwise its ID
+fn get_room_display_id(room: &Common) -> String {
+    match room.canonical_alias() {
+        Some(alias) => alias.to_string(),
+        None => room.room_id().to_string(),
+    }
+}
+
+fn get_event_id_from_str(event_id: &str) -> Box<EventId> {
+    <&EventId>::try_from(event_id)
+        .expect("Invalid event id")
+        .to_owned()
+}
+
+async fn get_event_json(client: &Client, room_id: &RoomId, event_id: &EventId) -> Result<Value> {
+    let request = GetRoomEventRequest::new(room_id, event_id);
+    let response = client.send(request, None).await?;
+    Ok(serde_json::from_str(response.event.json().get())?)
+}
+
+/// Fetch a page of room events going backwards from the token, along with the token for the
+/// next page (None once the start of the room is reached). Encrypted events are decrypted when
+/// we have the keys for them.
+async fn get_messages_page(
+    room: &Common,
+    from: &str,
+    limit: u32,
+) -> Result<(Vec<Value>, Option<String>)> {
+    let mut request = GetMessageEventsRequest::backward(room.room_id(), from);
+    request.limit = UInt::from(limit);
+    let response = room.messages(request).await?;
+    let events = response
+        .chunk
+        .iter()
+        .map(|event| serde_json::from_str(event.event.json().get()))
+        .collect::<Result<Vec<Value>, _>>()?;
+    let end = match events.is_empty() {
+        true => None,
+        false => response.end,
+    };
+    Ok((events, end))
+}
+
+/// Page backwards through the whole timeline of a room, returning the events oldest first
+async fn get_all_room_events(client: &Client, room: &Common) -> Result<Vec<Value>> {
+    let mut from = client.sync_token().await.expect("Missing sync token");
+    let mut events: Vec<Value> = Vec::new();
+    loop {
+        let (page, end) = get_messages_page(room, &from, 100).await?;
+        events.extend(page);
+        debug!("Fetched {} events from {}", events.len(), room.room_id());
+        match end {
+            Some(end) => from = end,
+            None => break,
+        }
+    }
+    events.reverse();
+    Ok(events)
+}
+
+/// Download the file of a media message, the SDK decrypts encrypted attachments
+async fn download_media(client: &Client, content: &Value, path: &Path) -> Result<()> {
+    let media_type = match content.get("file") {
+        Some(file) => MediaType::Encrypted(serde_json::from_value(file.clone())?),
+        None => MediaType::Uri(Box::<MxcUri>::from(
+            content["url"].as_str().unwrap_or_default(),
+        )),
+    };
+    let request = MediaRequest {
+        media_type,
+        format: MediaFormat::File,
+    };
+    let content = client.get_media_content(&request, false).await?;
+    std::fs::write(path, content)?;
+    Ok(())
+}
+
 async fn get_room_id_from_alias<'a>(client: &'a Client, alias: &'a RoomOrAliasId) -> Box<RoomId> {
     if alias.is_room_id() {
         <&RoomId>::try_from(alias.deref()).unwrap().to_owned()
@@ -589,3 +2576,981 @@ async fn get_room_id_from_alias<'a>(client: &'a Client, alias: &'a RoomOrAliasId
 fn get_room_name_from_opt_str(name: Option<String>) -> Option<Box<RoomName>> {
     name.map(|name| <&RoomName>::try_from(&name[..]).unwrap().to_owned())
 }
+
+/// Build a text, notice or emote message, optionally formatted as markdown or HTML
+fn build_text_message(msg: String, msgtype: &TextType, markdown: bool, html: bool) -> MessageType {
+    match msgtype {
+        TextType::Text => MessageType::Text(match (markdown, html) {
+            (true, _) => TextMessageEventContent::markdown(msg),
+            (_, true) => TextMessageEventContent::html(html_to_plain(&msg), msg),
+            _ => TextMessageEventContent::plain(msg),
+        }),
+        TextType::Notice => MessageType::Notice(match (markdown, html) {
+            (true, _) => NoticeMessageEventContent::markdown(msg),
+            (_, true) => NoticeMessageEventContent::html(html_to_plain(&msg), msg),
+            _ => NoticeMessageEventContent::plain(msg),
+        }),
+        TextType::Emote => MessageType::Emote(match (markdown, html) {
+            (true, _) => EmoteMessageEventContent::markdown(msg),
+            (_, true) => EmoteMessageEventContent::html(html_to_plain(&msg), msg),
+            _ => EmoteMessageEventContent::plain(msg),
+        }),
+    }
+}
+
+/// Point the m.relates_to of a message at the replied to event and/or thread root
+fn set_relation(content: &mut Value, reply_to: Option<&EventId>, thread: Option<&EventId>) {
+    let relation = match (reply_to, thread) {
+        (None, None) => return,
+        (Some(reply_to), None) => json!({ "m.in_reply_to": { "event_id": reply_to } }),
+        // clients without thread support see a reply to the thread root instead
+        (reply_to, Some(thread)) => json!({
+            "rel_type": "m.thread",
+            "event_id": thread,
+            "is_falling_back": reply_to.is_none(),
+            "m.in_reply_to": { "event_id": reply_to.unwrap_or(thread) },
+        }),
+    };
+    content["m.relates_to"] = relation;
+}
+
+/// Wrap new message content in an m.replace edit of the given event
+fn build_replacement(new_content: Value, event_id: &EventId) -> Value {
+    // clients without edit support show the fallback, marked with a *
+    let mut content = new_content.clone();
+    for field in ["body", "formatted_body"] {
+        if let Some(text) = new_content[field].as_str() {
+            content[field] = json!(format!("* {}", text));
+        }
+    }
+    content["m.new_content"] = new_content;
+    content["m.relates_to"] = json!({ "rel_type": "m.replace", "event_id": event_id });
+    content
+}
+
+/// Quote the replied to event in the body and formatted_body of a message
+fn add_reply_fallback(content: &mut Value, room_id: &RoomId, event_id: &EventId, original: &Value) {
+    let sender = original["sender"].as_str().unwrap_or_default();
+    let original_body =
+        strip_reply_fallback(original["content"]["body"].as_str().unwrap_or_default());
+    let original_html = match original["content"]["formatted_body"].as_str() {
+        Some(html) => strip_html_reply_fallback(html).to_owned(),
+        None => escape_html(&original_body).replace('\n', "<br />"),
+    };
+
+    let body = content["body"].as_str().unwrap_or_default().to_owned();
+    let html = match content["formatted_body"].as_str() {
+        Some(html) => html.to_owned(),
+        None => escape_html(&body).replace('\n', "<br />"),
+    };
+    let quote = original_body
+        .lines()
+        .enumerate()
+        .map(|(i, line)| match i {
+            0 => format!("> <{}> {}", sender, line),
+            _ => format!("> {}", line),
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    content["body"] = json!(format!("{}\n\n{}", quote, body));
+    content["format"] = json!("org.matrix.custom.html");
+    content["formatted_body"] = json!(format!(
+        "<mx-reply><blockquote><a href=\"https://matrix.to/#/{}/{}\">In reply to</a> \
+         <a href=\"https://matrix.to/#/{}\">{}</a><br />{}</blockquote></mx-reply>{}",
+        room_id, event_id, sender, sender, original_html, html
+    ));
+}
+
+/// Remove the quoted reply lines from the start of a plain text body
+fn strip_reply_fallback(body: &str) -> String {
+    match body.starts_with("> ") {
+        false => body.to_owned(),
+        true => body
+            .lines()
+            .skip_while(|line| line.starts_with('>'))
+            .skip_while(|line| line.is_empty())
+            .collect::<Vec<&str>>()
+            .join("\n"),
+    }
+}
+
+/// Remove the mx-reply block from the start of an HTML body
+fn strip_html_reply_fallback(html: &str) -> &str {
+    match html.find("</mx-reply>") {
+        None => html,
+        Some(end) => &html[end + "</mx-reply>".len()..],
+    }
+}
+
+/// Write exported events, each record holds the event along with its sender_name and media_path
+fn write_export(
+    out: &mut impl Write,
+    format: &ExportFormat,
+    room_id: &RoomId,
+    records: &[Value],
+) -> Result<()> {
+    match format {
+        ExportFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, records)?;
+            writeln!(out)?;
+        }
+        ExportFormat::Jsonl => {
+            for record in records {
+                serde_json::to_writer(&mut *out, record)?;
+                writeln!(out)?;
+            }
+        }
+        ExportFormat::Txt => {
+            for record in records {
+                if let Some(line) = summarize_export_record(record) {
+                    let ts = record["event"]["origin_server_ts"]
+                        .as_i64()
+                        .unwrap_or_default();
+                    writeln!(out, "[{}] {}", Utc.timestamp_millis(ts), line)?;
+                }
+            }
+        }
+        ExportFormat::Html => {
+            writeln!(out, "<!DOCTYPE html>")?;
+            writeln!(out, "<html>\n<head>\n<meta charset=\"utf-8\">")?;
+            writeln!(
+                out,
+                "<title>{}</title>\n</head>\n<body>",
+                escape_html(room_id.as_str())
+            )?;
+            writeln!(out, "<h1>{}</h1>", escape_html(room_id.as_str()))?;
+            for record in records {
+                let event = &record["event"];
+                let ts = event["origin_server_ts"].as_i64().unwrap_or_default();
+                let body = match event["type"].as_str() {
+                    Some("m.room.message") => {
+                        // never copy formatted_body, any room member could have put scripts
+                        // in it that would run when the archive is opened
+                        let text =
+                            escape_html(event["content"]["body"].as_str().unwrap_or_default())
+                                .replace('\n', "<br />");
+                        match record["media_path"].as_str() {
+                            Some(path) => format!("<a href=\"{}\">{}</a>", escape_html(path), text),
+                            None => text,
+                        }
+                    }
+                    _ => match summarize_export_record(record) {
+                        Some(line) => format!("<em>{}</em>", escape_html(&line)),
+                        None => continue,
+                    },
+                };
+                writeln!(
+                    out,
+                    "<div class=\"event\" id=\"{}\"><span class=\"date\">{}</span> <span class=\"sender\" title=\"{}\">{}</span> <span class=\"body\">{}</span></div>",
+                    escape_html(event["event_id"].as_str().unwrap_or_default()),
+                    Utc.timestamp_millis(ts),
+                    escape_html(event["sender"].as_str().unwrap_or_default()),
+                    escape_html(record["sender_name"].as_str().unwrap_or_default()),
+                    body,
+                )?;
+            }
+            writeln!(out, "</body>\n</html>")?;
+        }
+    };
+    Ok(())
+}
+
+/// One line description of an exported event for the text archive, None for events not worth
+/// showing there
+fn summarize_export_record(record: &Value) -> Option<String> {
+    let event = &record["event"];
+    let name = record["sender_name"].as_str().unwrap_or_default();
+    let content = &event["content"];
+    match event["type"].as_str()? {
+        "m.room.message" => {
+            let body = content["body"].as_str().unwrap_or_default();
+            Some(
+                match (content["msgtype"].as_str(), record["media_path"].as_str()) {
+                    (Some("m.emote"), _) => format!("* {} {}", name, body),
+                    (_, Some(path)) => format!("{}: {} ({})", name, body, path),
+                    _ => format!("{}: {}", name, body),
+                },
+            )
+        }
+        "m.room.member" => {
+            let member = event["state_key"].as_str().unwrap_or_default();
+            Some(format!(
+                "* {} {}",
+                member,
+                content["membership"]
+                    .as_str()
+                    .unwrap_or("changed membership")
+            ))
+        }
+        "m.room.name" => Some(format!(
+            "* {} changed the room name to {}",
+            name,
+            content["name"].as_str().unwrap_or_default()
+        )),
+        "m.room.topic" => Some(format!(
+            "* {} changed the topic to {}",
+            name,
+            content["topic"].as_str().unwrap_or_default()
+        )),
+        _ => None,
+    }
+}
+
+/// Replace anything that is not safe in a file name
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Upload a file and build the matching image, audio, video or file message for it
+async fn upload_attachment(client: &Client, file: &Path) -> Result<MessageType> {
+    let guess = mime_guess::from_path(file);
+    let mime = guess.first_or_octet_stream();
+    let body = get_file_name(file);
+    let size = UInt::new(std::fs::metadata(file)?.len());
+
+    let mut reader = File::open(file)?;
+    let response = client.upload(&mime, &mut reader).await?;
+    let url = response.content_uri;
+    info!("Uploaded {} to {}", file.display(), url);
+
+    let msgtype = match mime.type_().as_str() {
+        "image" => {
+            let mut info = ImageInfo::new();
+            info.mimetype = Some(mime.to_string());
+            info.size = size;
+            let (width, height) = get_image_dimensions(file);
+            info.width = width;
+            info.height = height;
+            MessageType::Image(ImageMessageEventContent::plain(
+                body,
+                url,
+                Some(Box::new(info)),
+            ))
+        }
+        "audio" => {
+            let mut info = AudioInfo::new();
+            info.mimetype = Some(mime.to_string());
+            info.size = size;
+            MessageType::Audio(AudioMessageEventContent::plain(
+                body,
+                url,
+                Some(Box::new(info)),
+            ))
+        }
+        "video" => {
+            let mut info = VideoInfo::new();
+            info.mimetype = Some(mime.to_string());
+            info.size = size;
+            MessageType::Video(VideoMessageEventContent::plain(
+                body,
+                url,
+                Some(Box::new(info)),
+            ))
+        }
+        _ => {
+            let mut info = FileInfo::new();
+            info.mimetype = Some(mime.to_string());
+            info.size = size;
+            MessageType::File(FileMessageEventContent::plain(
+                body,
+                url,
+                Some(Box::new(info)),
+            ))
+        }
+    };
+    Ok(msgtype)
+}
+
+/// The same info upload_attachment fills in, for the SDK to send with an encrypted attachment
+fn get_attachment_config(file: &Path, mime: &Mime) -> Result<AttachmentConfig> {
+    let size = UInt::new(std::fs::metadata(file)?.len());
+    let info = match mime.type_().as_str() {
+        "image" => {
+            let (width, height) = get_image_dimensions(file);
+            AttachmentInfo::Image(BaseImageInfo {
+                height,
+                width,
+                size,
+                blurhash: None,
+            })
+        }
+        "audio" => AttachmentInfo::Audio(BaseAudioInfo {
+            duration: None,
+            size,
+        }),
+        "video" => AttachmentInfo::Video(BaseVideoInfo {
+            duration: None,
+            height: None,
+            width: None,
+            size,
+            blurhash: None,
+        }),
+        _ => AttachmentInfo::File(BaseFileInfo { size }),
+    };
+    Ok(AttachmentConfig::new().info(info))
+}
+
+/// Width and height of an image, when its format is known
+fn get_image_dimensions(file: &Path) -> (Option<UInt>, Option<UInt>) {
+    match imagesize::size(file) {
+        Ok(dimensions) => (
+            UInt::new(dimensions.width as u64),
+            UInt::new(dimensions.height as u64),
+        ),
+        Err(_) => (None, None),
+    }
+}
+
+fn print_message(room: Option<&str>, sender: &str, ts: i64, body: &str) {
+    let date = Utc.timestamp_millis(ts);
+    if let Some(room) = room {
+        println!("Room: {}", room);
+    }
+    println!("From: {}\nDate: {}\nMessage: {}\n", sender, date, body);
+}
+
+/// The handlers only pick the event types, what they pass on is the event JSON as it was
+/// received, with every content field, also those ruma does not know about
+async fn register_listen_handlers(client: &Client, opts: ListenOptions) {
+    let handler_opts = opts.clone();
+    client
+        .register_event_handler(move |_: SyncRoomMessageEvent, room: Room, raw: RawEvent| {
+            let opts = handler_opts.clone();
+            async move { handle_listened_event(&opts, room, serde_json::from_str(raw.get())) }
+        })
+        .await;
+    let handler_opts = opts.clone();
+    client
+        .register_event_handler(
+            move |_: SyncRoomRedactionEvent, room: Room, raw: RawEvent| {
+                let opts = handler_opts.clone();
+                async move { handle_listened_event(&opts, room, serde_json::from_str(raw.get())) }
+            },
+        )
+        .await;
+    let handler_opts = opts.clone();
+    client
+        .register_event_handler(
+            move |_: SyncMessageEvent<ReactionEventContent>, room: Room, raw: RawEvent| {
+                let opts = handler_opts.clone();
+                async move { handle_listened_event(&opts, room, serde_json::from_str(raw.get())) }
+            },
+        )
+        .await;
+    let handler_opts = opts.clone();
+    client
+        .register_event_handler(
+            move |_: SyncMessageEvent<RoomEncryptedEventContent>, room: Room, raw: RawEvent| {
+                let opts = handler_opts.clone();
+                async move { handle_listened_event(&opts, room, serde_json::from_str(raw.get())) }
+            },
+        )
+        .await;
+    let handler_opts = opts;
+    client
+        .register_event_handler(
+            move |_: SyncStateEvent<RoomMemberEventContent>, room: Room, raw: RawEvent| {
+                let opts = handler_opts.clone();
+                async move { handle_listened_event(&opts, room, serde_json::from_str(raw.get())) }
+            },
+        )
+        .await;
+}
+
+/// Print an event received by message listen in the requested format, if it is wanted
+fn handle_listened_event(opts: &ListenOptions, room: Room, event: serde_json::Result<Value>) {
+    let room = match room {
+        Room::Joined(room) => room,
+        _ => return,
+    };
+    if !opts.includes_room(room.room_id()) {
+        return;
+    }
+    let event = match event {
+        Ok(event) => event,
+        Err(e) => {
+            warn!("Could not serialize event: {}", e);
+            return;
+        }
+    };
+    let (kind, body) = match summarize_event(&event) {
+        Some(summary) => summary,
+        None => return,
+    };
+    if kind == EventKind::Encrypted {
+        // anything decrypted arrives as its real type, so this one failed. Warn even when
+        // encrypted events are not shown, otherwise messages in this room go missing silently
+        warn!(
+            "Unable to decrypt {} in {}",
+            event["event_id"].as_str().unwrap_or_default(),
+            room.room_id()
+        );
+    }
+    if !opts.includes_kind(&kind) || !opts.includes_event(&event, &body) {
+        return;
+    }
+    // message wait only prints the first match, it is signalled once that is done
+    let matched = match &opts.matched {
+        None => None,
+        Some(matched) => match matched.lock().unwrap().take() {
+            Some(matched) => Some(matched),
+            None => return,
+        },
+    };
+
+    let room_name = get_room_display_id(&room);
+    let msgtype = event["content"]["msgtype"]
+        .as_str()
+        .or_else(|| event["type"].as_str())
+        .unwrap_or_default();
+    let record = json!({
+        "room_id": room.room_id(),
+        "room": room_name,
+        "event_id": event["event_id"],
+        "sender": event["sender"],
+        "timestamp": event["origin_server_ts"],
+        "type": event["type"],
+        "msgtype": msgtype,
+        "body": body,
+        "content": event["content"],
+    });
+    match opts.format {
+        ListenFormat::Text => print_message(
+            Some(&room_name),
+            event["sender"].as_str().unwrap_or_default(),
+            event["origin_server_ts"].as_i64().unwrap_or_default(),
+            &body,
+        ),
+        // json is kept as another name for jsonl, one event per line
+        ListenFormat::Json | ListenFormat::Jsonl => println!("{}", record),
+        ListenFormat::Raw => {
+            // sync events leave out the room, put it back so the line stands on its own
+            let mut raw = event.clone();
+            raw["room_id"] = json!(room.room_id());
+            println!("{}", raw);
+        }
+    };
+    if let Some(matched) = matched {
+        // the receiver is already gone if the wait timed out
+        let _ = matched.send(());
+    }
+
+    if let Some(cmd) = &opts.exec {
+        // run hooks in the background so a slow command does not hold up the sync
+        let cmd = cmd.clone();
+        let reply = opts.exec_reply;
+        let timeout = opts.exec_timeout;
+        let running = opts.exec_running.clone();
+        tokio::spawn(async move {
+            let _permit = running.acquire_owned().await;
+            if let Err(e) = run_exec_hook(&cmd, reply, timeout, &room, &record, &event).await {
+                warn!("Command {:?} failed: {}", cmd, e);
+            }
+        });
+    }
+}
+
+async fn load_bot(client: &Client, config: BotConfig) -> Result<Bot> {
+    let mut rooms: Vec<Box<RoomId>> = Vec::new();
+    for room in &config.rooms {
+        rooms.push(get_room_id_from_alias_str(client, room).await);
+    }
+    let default_timeout = humantime::parse_duration(&config.timeout)?;
+
+    let mut commands: Vec<BotCommand> = Vec::new();
+    for command in config.commands {
+        let mut command_rooms: Vec<Box<RoomId>> = Vec::new();
+        for room in &command.rooms {
+            command_rooms.push(get_room_id_from_alias_str(client, room).await);
+        }
+        if config.senders.is_empty() && command.senders.is_empty() {
+            warn!(
+                "Anyone can trigger {:?}, consider adding senders",
+                command.trigger
+            );
+        }
+        commands.push(BotCommand {
+            trigger: Regex::new(&command.trigger)?,
+            command: command.command,
+            rooms: command_rooms,
+            senders: command.senders,
+            timeout: match command.timeout {
+                Some(timeout) => humantime::parse_duration(&timeout)?,
+                None => default_timeout,
+            },
+            reply: command.reply,
+        });
+    }
+
+    Ok(Bot {
+        user_id: client.user_id().await.expect("Not logged in"),
+        rooms,
+        senders: config.senders,
+        commands,
+        running: Arc::new(Semaphore::new(config.max_concurrent.max(1))),
+    })
+}
+
+/// Run the first command whose trigger matches the message, if the room and sender are allowed
+fn handle_bot_message(bot: Arc<Bot>, event: SyncRoomMessageEvent, room: Room) {
+    let room = match room {
+        Room::Joined(room) => room,
+        _ => return,
+    };
+    // never answer ourselves, that is how feedback loops start
+    if event.sender == bot.user_id {
+        return;
+    }
+    let body = match &event.content.msgtype {
+        MessageType::Text(TextMessageEventContent { body, .. }) => match event.content.relates_to {
+            Some(Relation::Reply { .. }) => strip_reply_fallback(body),
+            _ => body.clone(),
+        },
+        _ => return,
+    };
+    let sender = event.sender.as_str();
+    let (index, captures) = match bot.commands.iter().enumerate().find_map(|(i, command)| {
+        match command.allows(&bot, room.room_id(), sender) {
+            true => command
+                .trigger
+                .captures(&body)
+                .map(|captures| (i, captures)),
+            false => None,
+        }
+    }) {
+        Some(found) => found,
+        None => return,
+    };
+
+    let mut envs = message_env(room.room_id(), &event, &body);
+    for (i, capture) in captures.iter().enumerate() {
+        let value = capture.map_or("", |m| m.as_str()).to_owned();
+        envs.push((format!("MATRIX_MATCH_{}", i), value));
+    }
+    for name in bot.commands[index].trigger.capture_names().flatten() {
+        let value = captures.name(name).map_or("", |m| m.as_str()).to_owned();
+        envs.push((format!("MATRIX_MATCH_{}", name.to_uppercase()), value));
+    }
+    let mut input = serde_json::to_value(&event).unwrap_or_default();
+    input["room_id"] = json!(room.room_id());
+
+    tokio::spawn(async move {
+        let command = &bot.commands[index];
+        let _permit = bot.running.clone().acquire_owned().await;
+        info!("Running {:?} for {}", command.command, input["sender"]);
+        let reply = match run_command(
+            &command.command,
+            &envs,
+            &input.to_string(),
+            command.reply,
+            Some(command.timeout),
+        )
+        .await
+        {
+            Ok(output) => output,
+            Err(e) => {
+                warn!("Command {:?} failed: {}", command.command, e);
+                format!("Command failed: {}", e)
+            }
+        };
+        if command.reply && !reply.is_empty() {
+            if let Err(e) = send_reply(&room, &input, reply).await {
+                warn!("Could not send reply: {}", e);
+            }
+        }
+    });
+}
+
+/// The MATRIX_* environment variables describing a message for commands to use
+fn message_env(
+    room_id: &RoomId,
+    event: &SyncRoomMessageEvent,
+    body: &str,
+) -> Vec<(String, String)> {
+    vec![
+        ("MATRIX_ROOM_ID".to_owned(), room_id.to_string()),
+        ("MATRIX_EVENT_ID".to_owned(), event.event_id.to_string()),
+        ("MATRIX_SENDER".to_owned(), event.sender.to_string()),
+        ("MATRIX_BODY".to_owned(), body.to_owned()),
+    ]
+}
+
+/// Run a command for a received event, passing the event JSON on stdin and the main fields in
+/// MATRIX_* environment variables, and optionally post its output back as a reply
+async fn run_exec_hook(
+    cmd: &str,
+    reply: bool,
+    timeout: Duration,
+    room: &Joined,
+    record: &Value,
+    event: &Value,
+) -> Result<()> {
+    let envs: Vec<(String, String)> = ["room_id", "event_id", "sender", "body"]
+        .iter()
+        .map(|field| {
+            (
+                format!("MATRIX_{}", field.to_uppercase()),
+                record[field].as_str().unwrap_or_default().to_owned(),
+            )
+        })
+        .collect();
+    // the same input the bot gives its commands, the event with its room
+    let mut input = event.clone();
+    input["room_id"] = record["room_id"].clone();
+    let output = run_command(cmd, &envs, &input.to_string(), reply, Some(timeout)).await?;
+    if reply && !output.is_empty() {
+        send_reply(room, event, output).await?;
+    }
+    Ok(())
+}
+
+/// Run a shell command with extra environment variables and input on stdin. Returns what it
+/// printed when capture is set, otherwise its output goes straight to our stdout.
+async fn run_command(
+    cmd: &str,
+    envs: &[(String, String)],
+    input: &str,
+    capture: bool,
+    timeout: Option<Duration>,
+) -> Result<String> {
+    let mut child = shell_command(cmd)
+        .envs(envs.iter().map(|(key, value)| (key, value)))
+        .stdin(Stdio::piped())
+        .stdout(match capture {
+            true => Stdio::piped(),
+            false => Stdio::inherit(),
+        })
+        .kill_on_drop(true)
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // write in the background, a command that never reads a large input would block us
+        // before the timeout starts
+        let input = input.to_owned();
+        let cmd = cmd.to_owned();
+        tokio::spawn(async move {
+            // commands that do not read their input close stdin early, that is fine
+            if let Err(e) = stdin.write_all(input.as_bytes()).await {
+                debug!("Could not write to stdin of {:?}: {}", cmd, e);
+            }
+        });
+    }
+    let output = match timeout {
+        None => child.wait_with_output().await?,
+        Some(timeout) => {
+            let pid = child.id();
+            match tokio::time::timeout(timeout, child.wait_with_output()).await {
+                Ok(output) => output?,
+                Err(_) => {
+                    // killing the shell alone would leave whatever it started running
+                    if let Some(pid) = pid {
+                        kill_process_group(pid);
+                    }
+                    anyhow::bail!("timed out after {}", humantime::format_duration(timeout))
+                }
+            }
+        }
+    };
+    if !output.status.success() {
+        anyhow::bail!("exited with {}", output.status);
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+fn shell_command(cmd: &str) -> Command {
+    let (shell, flag) = match cfg!(windows) {
+        true => ("cmd", "/C"),
+        false => ("sh", "-c"),
+    };
+    let mut command = Command::new(shell);
+    command.arg(flag).arg(cmd);
+    // its own process group, so a timeout can kill everything the command started
+    #[cfg(unix)]
+    command.process_group(0);
+    command
+}
+
+/// Kill a command started by shell_command, together with all its children
+#[cfg(unix)]
+fn kill_process_group(pid: u32) {
+    // the shell leads the process group, so the group id is its pid
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_process_group(_pid: u32) {}
+
+/// Reply to an event with a notice, staying in the same thread as the original event
+async fn send_reply(room: &Joined, original: &Value, body: String) -> Result<Box<EventId>> {
+    let event_id = get_event_id_from_str(original["event_id"].as_str().unwrap_or_default());
+    let relation = &original["content"]["m.relates_to"];
+    let thread = match relation["rel_type"].as_str() {
+        Some("m.thread") => relation["event_id"].as_str().map(get_event_id_from_str),
+        _ => None,
+    };
+
+    let mut content = serde_json::to_value(RoomMessageEventContent::new(build_text_message(
+        body,
+        &TextType::Notice,
+        false,
+        false,
+    )))?;
+    add_reply_fallback(&mut content, room.room_id(), &event_id, original);
+    set_relation(&mut content, Some(&event_id), thread.as_deref());
+    let response = room.send_raw(content, "m.room.message", None).await?;
+    Ok(response.event_id)
+}
+
+/// Work out what kind of event this is and a short human readable summary of it
+fn summarize_event(event: &Value) -> Option<(EventKind, String)> {
+    let content = &event["content"];
+    let sender = event["sender"].as_str().unwrap_or_default();
+    match event["type"].as_str()? {
+        "m.room.message" => {
+            let body = content["body"].as_str().unwrap_or_default().to_owned();
+            match content["msgtype"].as_str()? {
+                "m.text" => Some((EventKind::Text, body)),
+                "m.notice" => Some((EventKind::Notice, body)),
+                "m.emote" => Some((EventKind::Emote, format!("* {} {}", sender, body))),
+                "m.image" | "m.file" | "m.audio" | "m.video" => {
+                    let url = content["url"]
+                        .as_str()
+                        .or_else(|| content["file"]["url"].as_str())
+                        .unwrap_or_default();
+                    Some((EventKind::Media, format!("{} ({})", body, url)))
+                }
+                _ => None,
+            }
+        }
+        "m.room.encrypted" => Some((EventKind::Encrypted, "Unable to decrypt message".to_owned())),
+        "m.room.redaction" => Some((
+            EventKind::Redaction,
+            format!("redacted {}", event["redacts"].as_str().unwrap_or_default()),
+        )),
+        "m.reaction" => {
+            let relation = &content["m.relates_to"];
+            Some((
+                EventKind::Reaction,
+                format!(
+                    "reacted {} to {}",
+                    relation["key"].as_str().unwrap_or_default(),
+                    relation["event_id"].as_str().unwrap_or_default()
+                ),
+            ))
+        }
+        "m.room.member" => {
+            let member = event["state_key"].as_str().unwrap_or_default();
+            let previous = event["unsigned"]["prev_content"]["membership"].as_str();
+            let action = match (content["membership"].as_str()?, previous) {
+                ("join", Some("join")) => "changed their profile".to_owned(),
+                ("join", _) => "joined".to_owned(),
+                ("leave", Some("invite")) if member == sender => "rejected the invite".to_owned(),
+                ("leave", Some("ban")) => format!("was unbanned by {}", sender),
+                ("leave", _) if member == sender => "left".to_owned(),
+                ("leave", _) => format!("was kicked by {}", sender),
+                ("invite", _) => format!("was invited by {}", sender),
+                ("ban", _) => format!("was banned by {}", sender),
+                ("knock", _) => "knocked".to_owned(),
+                (membership, _) => membership.to_owned(),
+            };
+            Some((EventKind::Membership, format!("{} {}", member, action)))
+        }
+        _ => None,
+    }
+}
+
+/// Parse an RFC 3339 date time, or a plain YYYY-MM-DD date (midnight UTC)
+fn parse_date(date: &str) -> Result<DateTime<Utc>> {
+    match DateTime::parse_from_rfc3339(date) {
+        Ok(date) => Ok(date.with_timezone(&Utc)),
+        Err(_) => {
+            let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")?;
+            Ok(DateTime::from_utc(date.and_hms(0, 0, 0), Utc))
+        }
+    }
+}
+
+/// Parse a --since value, either a date or a duration before now
+fn parse_since(since: &str) -> Result<DateTime<Utc>> {
+    match humantime::parse_duration(since) {
+        Ok(duration) => Ok(Utc::now() - chrono::Duration::from_std(duration)?),
+        Err(_) => parse_date(since),
+    }
+}
+
+fn get_file_name(file: &Path) -> String {
+    file.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "file".to_owned())
+}
+
+/// Read the message body from the argument, stdin (when the argument is -) or a file
+fn read_message_body(msg: Option<String>, file: Option<PathBuf>) -> Result<String> {
+    let mut body = String::new();
+    match (msg, file) {
+        (_, Some(file)) => {
+            File::open(file)?.read_to_string(&mut body)?;
+        }
+        (Some(msg), None) if msg == "-" => {
+            std::io::stdin().read_to_string(&mut body)?;
+        }
+        (Some(msg), None) => body = msg,
+        (None, None) => anyhow::bail!("No message to send"),
+    };
+    Ok(body)
+}
+
+/// Split a message into chunks of at most max_size bytes, preferring line breaks
+fn split_message(body: &str, max_size: usize) -> Vec<String> {
+    let max_size = max_size.max(4);
+    let mut chunks: Vec<String> = Vec::new();
+    let mut current = String::new();
+    for line in body.split_inclusive('\n') {
+        if current.len() + line.len() > max_size && !current.is_empty() {
+            chunks.push(std::mem::take(&mut current));
+        }
+        let mut line = line;
+        // a single line that is too long has to be broken up on a char boundary
+        while line.len() > max_size {
+            let mut end = max_size;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            chunks.push(line[..end].to_owned());
+            line = &line[end..];
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+        .into_iter()
+        .map(|chunk| chunk.trim_end_matches('\n').to_owned())
+        .filter(|chunk| !chunk.is_empty())
+        .collect()
+}
+
+/// Build a plain text fallback for an HTML message body by stripping the tags
+fn html_to_plain(html: &str) -> String {
+    let mut plain = String::with_capacity(html.len());
+    let mut tag = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match (in_tag, c) {
+            (false, '<') => {
+                in_tag = true;
+                tag.clear();
+            }
+            (false, c) => plain.push(c),
+            (true, '>') => {
+                in_tag = false;
+                let name = tag
+                    .trim_start_matches('/')
+                    .split(|c: char| c.is_whitespace() || c == '/')
+                    .next()
+                    .unwrap_or("")
+                    .to_lowercase();
+                match name.as_str() {
+                    "br" | "p" | "div" | "li" | "pre" | "tr" | "h1" | "h2" | "h3" | "h4" | "h5"
+                    | "h6" | "blockquote" => {
+                        if !plain.is_empty() && !plain.ends_with('\n') {
+                            plain.push('\n');
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            (true, c) => tag.push(c),
+        }
+    }
+    plain
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+        .trim()
+        .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn html_to_plain_strips_tags() {
+        assert_eq!(
+            html_to_plain("<b>bold</b> and <i>italic</i>"),
+            "bold and italic"
+        );
+        assert_eq!(
+            html_to_plain("<p>one</p><p>two<br/>three</p>"),
+            "one\ntwo\nthree"
+        );
+        assert_eq!(html_to_plain("<ul><li>a</li><li>b</li></ul>"), "a\nb");
+    }
+
+    #[test]
+    fn html_to_plain_decodes_entities() {
+        assert_eq!(
+            html_to_plain("1 &lt; 2 &amp;&amp; 3 &gt; 2"),
+            "1 < 2 && 3 > 2"
+        );
+        assert_eq!(html_to_plain("&quot;hi&quot; it&#39;s"), "\"hi\" it's");
+        // an escaped entity stays an entity
+        assert_eq!(html_to_plain("&amp;lt;b&amp;gt;"), "&lt;b&gt;");
+    }
+
+    #[test]
+    fn split_message_short_body() {
+        assert_eq!(split_message("hello", 100), vec!["hello"]);
+        assert_eq!(split_message("", 100), Vec::<String>::new());
+    }
+
+    #[test]
+    fn split_message_on_line_breaks() {
+        assert_eq!(
+            split_message("one\ntwo\nthree", 8),
+            vec!["one\ntwo", "three"]
+        );
+    }
+
+    #[test]
+    fn split_message_long_line() {
+        let chunks = split_message(&"a".repeat(10), 4);
+        assert_eq!(chunks, vec!["aaaa", "aaaa", "aa"]);
+    }
+
+    #[test]
+    fn split_message_multibyte_at_split_point() {
+        // "é" is two bytes, the third one would be split at byte 5
+        let body = "éééé";
+        let chunks = split_message(body, 5);
+        assert_eq!(chunks, vec!["éé", "éé"]);
+        assert_eq!(chunks.concat(), body);
+        for chunk in split_message("a😀b😀c", 6) {
+            assert!(chunk.len() <= 6);
+        }
+        assert_eq!(split_message("a😀b😀c", 6).concat(), "a😀b😀c");
+    }
+}